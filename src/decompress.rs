@@ -0,0 +1,66 @@
+use std::{fs, io::{self, Read, Write}, path::Path};
+
+use tempfile;
+use lz4_flex;
+use ruzstd;
+
+use Result;
+
+pub(crate) const MOZLZ4_MAGIC: &[u8] = b"mozLz40\0";
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+// Decompresses the contents of a mozlz4 file (the size, then an lz4 block)
+// after its magic bytes. `path` is just for errors.
+pub(crate) fn mozlz4_decode(compressed: &[u8], path: &Path) -> Result<Vec<u8>> {
+    if compressed.len() < 4 {
+        bail!(BadFile, "Truncated mozlz4 file: {:?}", path);
+    }
+    let size = (compressed[0] as usize) | (compressed[1] as usize) << 8 |
+               (compressed[2] as usize) << 16 | (compressed[3] as usize) << 24;
+    lz4_flex::block::decompress(&compressed[4..], size).map_err(|e|
+        format_err!(BadFile, "Failed to decompress mozlz4 file {:?}: {}", path, e))
+}
+
+// Compresses `data` into a mozlz4 file's contents, magic bytes and all.
+pub(crate) fn mozlz4_encode(data: &[u8]) -> Vec<u8> {
+    let mut file = MOZLZ4_MAGIC.to_vec();
+    file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    file.extend_from_slice(&lz4_flex::block::compress(data));
+    file
+}
+
+// If `path` is a mozlz4 or zstd compressed file (detected by its magic bytes),
+// decompresses it into a temporary file and returns that. Returns None for
+// files that aren't compressed.
+pub(crate) fn decompress_source(path: &Path) -> Result<Option<tempfile::NamedTempFile>> {
+    let mut header = [0u8; 8];
+    let mut file = fs::File::open(path)?;
+    let header_len = file.read(&mut header)?;
+    let header = &header[..header_len];
+    let data = if header.starts_with(MOZLZ4_MAGIC) {
+        info!("Decompressing mozlz4 file {:?}", path);
+        let mut compressed = vec![];
+        file.read_to_end(&mut compressed)?;
+        mozlz4_decode(&compressed, path)?
+    } else if header.starts_with(ZSTD_MAGIC) {
+        info!("Decompressing zstd file {:?}", path);
+        let file = fs::File::open(path)?;
+        let mut decoder = ruzstd::StreamingDecoder::new(io::BufReader::new(file)).map_err(|e|
+            format_err!(BadFile, "Failed to decompress zstd file {:?}: {}", path, e))?;
+        let mut data = vec![];
+        decoder.read_to_end(&mut data).map_err(|e|
+            format_err!(BadFile, "Failed to decompress zstd file {:?}: {}", path, e))?;
+        data
+    } else {
+        return Ok(None);
+    };
+    if !data.starts_with(SQLITE_MAGIC) {
+        bail!(BadFile, "Decompressed {:?}, but the result isn't a SQLite database", path);
+    }
+    let mut temp = tempfile::NamedTempFile::new()?;
+    temp.write_all(&data)?;
+    temp.flush()?;
+    debug!("Decompressed {:?} to {:?} ({} bytes)", path, temp.path(), data.len());
+    Ok(Some(temp))
+}
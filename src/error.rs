@@ -0,0 +1,103 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use failure::Fail;
+use rand;
+use rusqlite;
+
+/// Why something failed, for callers that need to tell failures apart. Most
+/// carry the message we'd show the user, which says what exactly went wrong.
+#[derive(Debug)]
+pub enum Error {
+    /// We couldn't find the profiles, or the one asked for.
+    ProfileDiscovery(String),
+    /// The browser has the database locked, so we couldn't copy it.
+    SourceLocked(PathBuf),
+    /// The input isn't a database (or schema version) we know how to
+    /// anonymize.
+    SchemaUnsupported(String),
+    /// The output already exists, and we weren't told to overwrite it.
+    OutputExists(PathBuf),
+    /// The output would go over the input, or into a browser profile.
+    UnsafeOutput(String),
+    /// The input or the output is corrupt.
+    Corrupt(String),
+    /// The options are out of range, or don't go together.
+    BadOptions(String),
+    /// A compressed input, session store, mapping or policy file couldn't be
+    /// read, or isn't what it should be.
+    BadFile(String),
+    /// The output failed one of our checks, so it was removed.
+    Verification(String),
+    /// There isn't enough disk space for the copies we need to make.
+    NoSpace(String),
+    /// `Options::cancel` was set.
+    Cancelled(String),
+    /// We ran for longer than `Options::max_runtime`.
+    TimedOut(String),
+    /// SQLite failed, other than for the reasons above.
+    Sqlite(rusqlite::Error),
+    /// Reading or writing a file failed, other than for the reasons above.
+    Io(io::Error),
+    /// Anything else, like the system's random number generator failing.
+    Other(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::SourceLocked(ref path) =>
+                write!(f, "{:?} is locked by the browser using it; close it and try again", path),
+            Error::OutputExists(ref path) => write!(f, "{:?} already exists, pass -f to overwrite it", path),
+            Error::Sqlite(ref e) => e.fmt(f),
+            Error::Io(ref e) => e.fmt(f),
+            Error::ProfileDiscovery(ref msg) | Error::SchemaUnsupported(ref msg) | Error::UnsafeOutput(ref msg) |
+            Error::Corrupt(ref msg) | Error::BadOptions(ref msg) | Error::BadFile(ref msg) |
+            Error::Verification(ref msg) | Error::NoSpace(ref msg) | Error::Cancelled(ref msg) |
+            Error::TimedOut(ref msg) | Error::Other(ref msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Fail for Error {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match *self {
+            Error::Sqlite(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<rand::Error> for Error {
+    fn from(e: rand::Error) -> Error {
+        Error::Other(format!("Can't get random numbers: {}", e))
+    }
+}
+
+// Like failure's, but with the kind of error first:
+// `bail!(BadOptions, "Bad scrub level {}", level)`.
+macro_rules! bail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err(format_err!($kind, $($arg)+))
+    };
+}
+
+macro_rules! format_err {
+    ($kind:ident, $($arg:tt)+) => {
+        ::Error::$kind(format!($($arg)+))
+    };
+}
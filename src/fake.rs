@@ -0,0 +1,49 @@
+use rand::{Rng, rngs::StdRng};
+
+// Words that fake hosts and slugs are made of, like `blue-otter`.
+const ADJECTIVES: &[&str] = &[
+    "amber", "blue", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "daring",
+    "eager", "fancy", "gentle", "golden", "happy", "jolly", "keen", "lively", "lucky", "mellow",
+    "misty", "noble", "proud", "quiet", "rapid", "rustic", "shiny", "silent", "snowy", "sunny",
+    "swift", "witty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "beacon", "canyon", "cedar", "comet", "falcon", "fern", "fox", "garden", "harbor",
+    "heron", "island", "lantern", "maple", "meadow", "otter", "panda", "pebble", "pine", "prairie",
+    "raven", "river", "robin", "saddle", "sparrow", "summit", "thistle", "tiger", "valley",
+    "walrus", "willow", "zephyr",
+];
+
+// Words that fake titles and text are made of.
+const LOREM: &[&str] = &[
+    "lorem", "ipsum", "dolor", "sit", "amet", "consectetur", "adipiscing", "elit", "sed", "do",
+    "eiusmod", "tempor", "incididunt", "ut", "labore", "et", "dolore", "magna", "aliqua", "enim",
+    "ad", "minim", "veniam", "quis", "nostrud", "exercitation", "ullamco", "laboris", "nisi",
+    "aliquip", "ex", "ea", "commodo", "consequat", "duis", "aute", "irure", "in", "reprehenderit",
+    "voluptate", "velit", "esse", "cillum", "fugiat", "nulla", "pariatur",
+];
+
+fn pick<'a>(rng: &mut StdRng, words: &[&'a str]) -> &'a str {
+    words[rng.gen::<u32>() as usize % words.len()]
+}
+
+// A slug like `swift-heron`, for hosts and path segments.
+pub(crate) fn slug(rng: &mut StdRng) -> String {
+    format!("{}-{}", pick(rng, ADJECTIVES), pick(rng, NOUNS))
+}
+
+// `count` words of lorem ipsum, lowercase and separated by spaces.
+pub(crate) fn words(rng: &mut StdRng, count: usize) -> String {
+    (0..count).map(|_| pick(rng, LOREM)).collect::<Vec<_>>().join(" ")
+}
+
+// Like `words`, but capitalized like a title.
+pub(crate) fn phrase(rng: &mut StdRng, count: usize) -> String {
+    let words = words(rng, count);
+    let mut chars = words.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => words,
+    }
+}
@@ -0,0 +1,85 @@
+// A port of the url hashing Firefox uses for `moz_places.url_hash`
+// (`HashURL` in toolkit/components/places/Helpers.cpp), so that lookups by
+// hash keep working on the anonymized urls.
+
+const GOLDEN_RATIO: u32 = 0x9E37_79B9;
+
+// Firefox only hashes the start of very long urls.
+const MAX_CHARS_TO_HASH: usize = 1500;
+
+// mozilla::AddToHash
+fn add_to_hash(hash: u32, value: u32) -> u32 {
+    (hash.rotate_left(5) ^ value).wrapping_mul(GOLDEN_RATIO)
+}
+
+// mozilla::HashString, which hashes the bytes as unsigned chars.
+fn hash_bytes(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |hash, &b| add_to_hash(hash, u32::from(b)))
+}
+
+// URI-like strings (with a prefix before a colon in the first 50 bytes) get a
+// 48 bit hash: the low 16 bits of the prefix's hash, followed by the 32 bit
+// hash of the whole url. Anything else just gets the 32 bit hash.
+pub(crate) fn hash_url(url: &str) -> u64 {
+    let bytes = url.as_bytes();
+    let hash = u64::from(hash_bytes(&bytes[..bytes.len().min(MAX_CHARS_TO_HASH)]));
+    match bytes[..bytes.len().min(50)].iter().position(|&b| b == b':') {
+        Some(colon) => {
+            let prefix_hash = u64::from(hash_bytes(&bytes[..colon]) & 0xFFFF);
+            (prefix_hash << 32) + hash
+        }
+        None => hash,
+    }
+}
+
+// Firefox's `fixup_url` SQL function, which favicons.sqlite hashes icon urls
+// with: the url without an http or https scheme, or a leading `www.`.
+pub(crate) fn fixup_url(url: &str) -> &str {
+    let url = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")).unwrap_or(url);
+    url.strip_prefix("www.").unwrap_or(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Worked out independently from mfbt's AddToHash and HashString and
+    // Places' HashURL, rather than with this code.
+    #[test]
+    fn hash_url_known_answers() {
+        assert_eq!(hash_url("https://www.mozilla.org/"), 47358155560141);
+        assert_eq!(hash_url("http://example.com/"), 125508428684077);
+        assert_eq!(hash_url("about:blank"), 175532304468422);
+        assert_eq!(hash_url("place:"), 268507548246873);
+        assert_eq!(hash_url("moz-extension://0a1b/popup.html"), 227112866191782);
+        // Bytes above 0x7f are hashed unsigned.
+        assert_eq!(hash_url("https://example.com/café"), 47359140643744);
+        assert_eq!(hash_url("https://例え.jp/パス"), 47359450554183);
+    }
+
+    #[test]
+    fn hash_url_puts_the_prefix_hash_above_the_url_hash() {
+        let hash = hash_url("https://www.mozilla.org/");
+        assert_eq!(hash >> 32, u64::from(hash_bytes(b"https") & 0xFFFF));
+        assert_eq!(hash >> 32, 11026);
+        assert_eq!(hash & 0xFFFF_FFFF, 1846154445);
+        assert_eq!(hash_url("http://example.com/") >> 32, 29222);
+        // Without a colon in the first 50 bytes, there's no prefix.
+        assert_eq!(hash_url("no colon here"), 1346982265);
+        assert_eq!(hash_url(&format!("{}:y", "x".repeat(60))), 1165879805);
+    }
+
+    #[test]
+    fn hash_url_only_hashes_the_start_of_long_urls() {
+        let url = format!("https://example.com/{}", "a".repeat(2000));
+        assert_eq!(hash_url(&url), 47360041867706);
+        assert_eq!(hash_url(&url), hash_url(&url[..MAX_CHARS_TO_HASH]));
+    }
+
+    #[test]
+    fn fixup_url_strips_the_scheme_and_www() {
+        assert_eq!(fixup_url("https://www.example.com/favicon.ico"), "example.com/favicon.ico");
+        assert_eq!(fixup_url("http://example.com/"), "example.com/");
+        assert_eq!(fixup_url("ftp://www.example.com/"), "ftp://www.example.com/");
+    }
+}
@@ -0,0 +1,1669 @@
+//! Anonymizes Firefox `places.sqlite` databases, replacing every string with
+//! random text of the same length while keeping the structure (ids, foreign
+//! keys, counts and dates) intact.
+//!
+//! The `anonymize-places` binary is a thin command line wrapper around
+//! [`anonymize_places`].
+
+extern crate rusqlite;
+extern crate libsqlite3_sys as ffi;
+extern crate dirs;
+
+extern crate failure;
+#[macro_use]
+extern crate log;
+extern crate rand;
+extern crate tempfile;
+extern crate lz4_flex;
+extern crate ruzstd;
+extern crate hmac;
+extern crate sha2;
+extern crate url;
+extern crate serde_json;
+extern crate toml;
+extern crate publicsuffix;
+extern crate ring;
+#[cfg(unix)]
+extern crate libc;
+
+use std::{fs, path::{Path, PathBuf}};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use rusqlite::{Connection, OpenFlags};
+use rand::{prelude::*, rngs::{OsRng, StdRng}};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+#[macro_use]
+mod error;
+mod anonymizer;
+mod decompress;
+mod fake;
+mod hash;
+mod mapping;
+mod policy;
+mod profiles;
+mod progress;
+mod salvage;
+mod schema;
+mod sessionstore;
+mod snapshot;
+mod space;
+mod spill;
+mod verify;
+mod watchdog;
+
+pub use anonymizer::StringAnonymizer;
+pub use error::Error;
+pub use mapping::mapping_entries;
+pub use policy::{Policies, Policy};
+pub use profiles::{Profile, ProfileStats, get_profiles, get_profiles_in_dir, sort_profiles, find_profile, format_prtime};
+pub use schema::schema_version;
+pub use verify::{Leak, find_leaks};
+
+use anonymizer::{url_fingerprint, strip_query, is_bookmark_root};
+use decompress::decompress_source;
+use hash::{hash_url, fixup_url};
+use mapping::{read_mapping, write_mapping};
+use profiles::is_profile_dir;
+use progress::Progress;
+use salvage::salvage;
+use schema::*;
+use sessionstore::SessionStore;
+use snapshot::{snapshot, sidecar};
+use space::check_free_space;
+use verify::{thorough_verify, check_integrity, check_references};
+use watchdog::Watchdog;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Options for [`anonymize_places`]. The defaults match running the command
+/// line tool without any flags.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Keep the structure of download annotations, anonymizing the file paths
+    /// in them segment by segment. Implied by `scrub_level` 0.
+    pub anonymize_downloads: bool,
+    /// Record a salted hash of each original url in a `url_fingerprints`
+    /// table. Not allowed with `scrub_level` 5.
+    pub fingerprint_salt: Option<String>,
+    /// How much to scrub, from 0 (keep structure) to 5 (scrub everything).
+    /// Each level turns on other options (on top of any already set):
+    ///
+    /// - 0: `keep_url_structure`, `keep_public_suffix`, `keep_query_keys`,
+    ///   `keep_title_structure` and `anonymize_downloads`, so only the
+    ///   values in urls, titles and download paths are anonymized.
+    /// - 1: `keep_url_structure`, so urls keep their scheme and shape, but
+    ///   public suffixes, query keys, titles and download paths are
+    ///   anonymized too.
+    /// - 2 (the default): urls are anonymized whole.
+    /// - 3: also reduces every count (visit counts, frecencies and the like)
+    ///   to 0 or 1.
+    /// - 4: also `round_dates` to the day, unless it's already set.
+    /// - 5: also `regenerate_guids`, `strip_query`, `strip_favicons` and a
+    ///   `length_bucket` of 16 (unless it's already set), zeroes every count
+    ///   and date, and refuses `fingerprint_salt` and `key`.
+    pub scrub_level: u8,
+    /// Fail if the database has tables we don't recognize.
+    pub strict: bool,
+    /// Unrecognized tables that `strict` should accept.
+    pub allowed_tables: Vec<String>,
+    /// Give up (and remove the partial output) after this long.
+    pub max_runtime: Option<Duration>,
+    /// If the input is corrupt, anonymize what can still be read of it
+    /// rather than failing.
+    pub salvage: bool,
+    /// Compare every cell of the output against the source afterwards, and
+    /// check both databases with `PRAGMA integrity_check` rather than the
+    /// quicker `quick_check`.
+    pub thorough_verify: bool,
+    /// Rebuild the output's indexes and statistics (with `REINDEX` and
+    /// `ANALYZE`) once it's anonymized, so queries on it perform like they
+    /// would on the original, for benchmarking.
+    pub optimize: bool,
+    /// `VACUUM` the output once it's anonymized, which can take longer than
+    /// anonymizing it. Without it, only databases with incremental
+    /// auto-vacuum (like Firefox's) shrink, and the output can still hold
+    /// deleted data from the input: what we delete or overwrite is zeroed,
+    /// but the free pages and gaps in pages the input already had are copied
+    /// as they are, with whatever history was deleted before.
+    pub vacuum: bool,
+    /// Fail unless the output is at least this many percent smaller than the input.
+    pub min_size_reduction: Option<f64>,
+    /// Skip the database unless its schema version is this.
+    pub only_schema_version: Option<i64>,
+    /// Seed for the random replacements. Anonymizing the same input with the
+    /// same seed gives a byte-identical output.
+    pub seed: Option<u64>,
+    /// Derive each replacement from an HMAC of the original under this key
+    /// instead of at random, so it's the same across runs. Takes precedence
+    /// over `seed`, and isn't allowed with `scrub_level` 5.
+    pub key: Option<Vec<u8>>,
+    /// Anonymize urls piece by piece so they still parse as urls of the same
+    /// shape, rather than replacing them as a whole. Only then does each url
+    /// start with the (anonymized) prefix and host of its origin.
+    pub keep_url_structure: bool,
+    /// Replace place and bookmark GUIDs with new random (but valid and
+    /// unique) ones, rather than anonymizing them like any other text. The
+    /// bookmark roots keep theirs.
+    pub regenerate_guids: bool,
+    /// Anonymize databases with a newer schema version than we know about,
+    /// treating anything new generically, rather than failing.
+    pub allow_unknown_schema_version: bool,
+    /// Anonymize any SQLite database, going only by its columns' names and
+    /// `policies`, without the rules for the databases we know (or refusing
+    /// ones that aren't places databases).
+    pub generic: bool,
+    /// Per-table and per-column policies that override the built-in rules.
+    pub policies: Policies,
+    /// Only anonymize these tables, if given, and copy the rest as is.
+    pub tables: Option<Vec<String>>,
+    /// Copy these tables as is, without anonymizing them.
+    pub exclude_tables: Vec<String>,
+    /// Move every date by the same random offset (of up to a year either
+    /// way), keeping their order and the intervals between them.
+    pub shift_dates: bool,
+    /// Truncate every date to a multiple of this, like an hour or a day.
+    pub round_dates: Option<Duration>,
+    /// Scale each positive count (visit counts, frecencies, use counts and
+    /// the like) by a random factor within this many percent either way.
+    /// They stay positive and roughly in order.
+    pub count_noise: Option<f64>,
+    /// Hosts to leave as they are, along with their subdomains and the urls
+    /// and origins on them. Everything else about those pages (like their
+    /// titles) is still anonymized.
+    pub keep_hosts: Vec<String>,
+    /// Keep the public suffix of each host (like `com` or `co.uk`) and only
+    /// anonymize the labels before it.
+    pub keep_public_suffix: bool,
+    /// Remove the query string and fragment from every url before
+    /// anonymizing it.
+    pub strip_query: bool,
+    /// With `keep_url_structure`, keep the names of query parameters and
+    /// only anonymize their values.
+    pub keep_query_keys: bool,
+    /// Anonymize titles word by word, keeping their spaces and punctuation,
+    /// rather than as a whole.
+    pub keep_title_structure: bool,
+    /// Pad replacement text to a multiple of this many characters, so it
+    /// doesn't give away the exact length of the original. GUIDs, host labels
+    /// and origin prefixes keep their length.
+    pub length_bucket: Option<usize>,
+    /// Seed every random choice straight from the operating system's RNG,
+    /// failing if it's unavailable rather than falling back to a weaker
+    /// source of entropy. Can't be used with `seed` or `key`, which are
+    /// reproducible by design.
+    pub secure_rng: bool,
+    /// How text is replaced.
+    pub mode: Mode,
+    /// Write what each string was replaced with to this file, encrypted
+    /// with `mapping_passphrase`, so replacements can be looked up later by
+    /// whoever has the passphrase.
+    pub export_mapping: Option<PathBuf>,
+    /// Reuse the replacements in this file (written by `export_mapping` in
+    /// an earlier run, and decrypted with `mapping_passphrase`), so strings
+    /// that were anonymized then get the same replacements again.
+    pub import_mapping: Option<PathBuf>,
+    /// The passphrase for `export_mapping` and `import_mapping`.
+    pub mapping_passphrase: Option<String>,
+    /// Remove the icon images from favicons.sqlite, which can identify the
+    /// sites they're from even with their urls anonymized.
+    pub strip_favicons: bool,
+    /// Replace cookie values with keyed hashes (so equal values stay equal)
+    /// rather than wiping them.
+    pub hash_cookie_values: bool,
+    /// Work out what would be done, and the SQL that would do it, without
+    /// writing `output` (or an exported mapping).
+    pub dry_run: bool,
+    /// Count what was done to each table, for [`Outcome::Anonymized`]. Costs
+    /// a scan of each table.
+    pub stats: bool,
+    /// Show which phase we're in (copying, anonymizing each table,
+    /// verifying) and for how long on a status line on stderr.
+    pub progress: bool,
+    /// How each table is updated.
+    pub engine: Engine,
+    /// Set this to stop anonymizing, from another thread or a signal
+    /// handler. The statement running is interrupted (or, with
+    /// [`Engine::Rows`], we stop between chunks of rows), and the partial
+    /// output is removed.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// With [`Engine::Rows`], commit every this many rows, rather than once
+    /// per table, so the journal of a big table doesn't grow without bound.
+    pub batch_size: Option<u64>,
+    /// Keep the output's journal in memory and don't sync it to disk while
+    /// anonymizing (its usual journal mode is put back at the end). The
+    /// output is only a copy, so a crash loses nothing that can't be redone.
+    pub fast_writes: bool,
+    /// Work out the replacements for each table's text and urls on this many
+    /// threads before updating it. Replacements are then derived from a
+    /// random key (unless there's already a `key`), which is just as random.
+    /// Has no effect with a `seed`, whose replacements depend on the order
+    /// strings are anonymized in, and little in modes other than
+    /// [`Mode::Random`].
+    pub threads: usize,
+    /// Keep at most about this many bytes of replacements in memory, and
+    /// the rest in a temporary database, for databases with more distinct
+    /// strings than fit in memory.
+    pub memory_budget: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            anonymize_downloads: false,
+            fingerprint_salt: None,
+            // Level 2 is what we do without any other options.
+            scrub_level: 2,
+            strict: false,
+            allowed_tables: vec![],
+            max_runtime: None,
+            salvage: false,
+            thorough_verify: false,
+            optimize: false,
+            vacuum: true,
+            min_size_reduction: None,
+            only_schema_version: None,
+            seed: None,
+            key: None,
+            keep_url_structure: false,
+            regenerate_guids: false,
+            allow_unknown_schema_version: false,
+            generic: false,
+            policies: Policies::default(),
+            tables: None,
+            exclude_tables: vec![],
+            shift_dates: false,
+            round_dates: None,
+            count_noise: None,
+            keep_hosts: vec![],
+            keep_public_suffix: false,
+            strip_query: false,
+            keep_query_keys: false,
+            keep_title_structure: false,
+            length_bucket: None,
+            secure_rng: false,
+            mode: Mode::Random,
+            export_mapping: None,
+            import_mapping: None,
+            mapping_passphrase: None,
+            strip_favicons: false,
+            hash_cookie_values: false,
+            dry_run: false,
+            stats: false,
+            progress: false,
+            engine: Engine::Sql,
+            cancel: None,
+            batch_size: None,
+            fast_writes: true,
+            threads: 1,
+            memory_budget: None,
+        }
+    }
+}
+
+/// How [`anonymize_places`] replaces text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// With random text that looks like the original, always the same for
+    /// the same original.
+    Random,
+    /// With a fixed value for each kind of column, like `<REDACTED>` or
+    /// `https://redacted.invalid/`, so nothing at all is left of the original.
+    /// Values of UNIQUE columns are numbered to keep them unique, and GUIDs
+    /// and origin prefixes are anonymized as usual, as they must stay valid.
+    Redact,
+    /// With readable tokens numbering each value among those of its kind,
+    /// like `host-0001`, `url-0421` or `title-0099`, always the same for the
+    /// same value, which makes them easy to refer to in bug reports. GUIDs
+    /// and origin prefixes are anonymized as usual.
+    Sequential,
+    /// With a keyed SHA-256 digest of each value, truncated to 32 hex digits.
+    /// Nothing is remembered, so this scales to any size of database. The key
+    /// is `key`, if given, or else a random one. GUIDs and origin prefixes are
+    /// anonymized as usual.
+    Hash,
+    /// With realistic looking fake data: hosts like `blue-otter.example`,
+    /// lorem ipsum titles, and urls made of fake hosts and slugs, for
+    /// screenshots and demos. GUIDs and origin prefixes are anonymized as
+    /// usual.
+    Fake,
+    /// With random text like the original, derived from a keyed hash of it
+    /// alone (see [`StringAnonymizer::stream`]), so nothing is remembered,
+    /// and databases with more distinct strings than fit in memory can be
+    /// anonymized. Text shorter than 12 characters is lengthened to 12, so
+    /// different values don't end up the same. GUIDs are replaced the same
+    /// way, and origin prefixes are anonymized as usual.
+    Stream,
+}
+
+/// How [`anonymize_places`] updates each table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    /// With a single UPDATE of the whole table, which is fastest.
+    Sql,
+    /// A chunk of rows at a time, in rowid order, reporting how many rows a
+    /// second it manages and stopping cleanly between chunks when `cancel`
+    /// is set. Tables without rowids are updated in one go.
+    Rows,
+}
+
+/// What [`anonymize_places`] did.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// `output` holds the anonymized database, which had this schema version
+    /// (0 for session stores). With `stats`, `tables` says what was done to
+    /// each of its tables.
+    Anonymized { schema_version: i64, tables: Vec<TableStats> },
+    /// The input didn't match `only_schema_version`, so nothing was written.
+    Skipped { schema_version: i64 },
+    /// This was a dry run, so nothing was written, but this is what would
+    /// have been.
+    DryRun(DryRun),
+}
+
+/// What [`anonymize_places`] did to a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableStats {
+    pub name: String,
+    /// How many rows it had.
+    pub rows: i64,
+    /// How many rows were rewritten.
+    pub updated_rows: i64,
+    /// How many rows the policies deleted.
+    pub deleted_rows: i64,
+    /// How many distinct strings were anonymized.
+    pub distinct_strings: i64,
+}
+
+/// What a dry run found [`anonymize_places`] would do.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DryRun {
+    /// Each table in the database.
+    pub tables: Vec<TablePlan>,
+    /// The SQL that would run, in order.
+    pub sql: Vec<String>,
+}
+
+/// What a dry run found would happen to a table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TablePlan {
+    pub name: String,
+    /// How many rows it has.
+    pub rows: i64,
+    /// How many of those the policies would delete.
+    pub deleted_rows: i64,
+    /// Whether it's copied as is, as it wasn't selected.
+    pub left_as_is: bool,
+    /// The columns that would be anonymized, apart from numbers and dates
+    /// (which are only changed by the options that scrub them).
+    pub columns: Vec<String>,
+}
+
+// How many rows `Engine::Rows` updates at a time.
+const CHUNK_ROWS: i64 = 10_000;
+
+// Runs `update` (made with `chunked`) over `table` a chunk of rows at a time,
+// in one transaction or one every `batch_size` rows, reporting the rate on the
+// status line and stopping between chunks if cancelled. Returns how many rows
+// it changed.
+fn update_in_chunks(conn: &Connection, table: &str, update: &str, progress: &Progress,
+                    batch_size: Option<u64>, cancel: Option<&AtomicBool>) -> Result<i32> {
+    let mut next_end = conn.prepare(&format!(
+        "SELECT rowid FROM {} WHERE rowid > ?1 ORDER BY rowid LIMIT 1 OFFSET ?2", table))?;
+    let (rows, last): (i64, Option<i64>) = conn.query_row(
+        &format!("SELECT count(*), max(rowid) FROM {}", table), &[], |row| (row.get(0), row.get(1)))?;
+    let last = match last {
+        Some(last) => last,
+        None => return Ok(0),
+    };
+    let batch_size = batch_size.map_or(i64::MAX, |n| n.min(i64::MAX as u64) as i64);
+    debug!("Executing sql in chunks of {} rows:\n{}", CHUNK_ROWS.min(batch_size), update);
+    let mut update = conn.prepare(update)?;
+    let started = Instant::now();
+    let (mut start, mut rows_done, mut in_batch, mut updated) = (i64::MIN, 0, 0, 0);
+    conn.execute_batch("BEGIN")?;
+    while start < last {
+        if cancel.is_some_and(|c| c.load(Ordering::SeqCst)) {
+            conn.execute_batch("ROLLBACK")?;
+            bail!(Cancelled, "Cancelled while anonymizing {}", table);
+        }
+        // Chunks stop at the end of the batch, so we commit exactly every
+        // `batch_size` rows, and the next batch starts after the last rowid
+        // committed.
+        let chunk = CHUNK_ROWS.min(batch_size - in_batch);
+        let end = match next_end.query_row(&[&start, &(chunk - 1)], |row| row.get(0)) {
+            Ok(end) => end,
+            Err(rusqlite::Error::QueryReturnedNoRows) => last,
+            Err(e) => return Err(e.into()),
+        };
+        updated += update.execute(&[&start, &end])?;
+        // Every chunk but the last is full.
+        rows_done = rows.min(rows_done + chunk);
+        in_batch += chunk;
+        start = end;
+        if in_batch == batch_size && start < last {
+            debug!("Committing after {} rows of {}", rows_done, table);
+            conn.execute_batch("COMMIT; BEGIN")?;
+            in_batch = 0;
+        }
+        let secs = started.elapsed().as_secs_f64();
+        progress.detail(format!("{} of {} rows, {:.0} rows/s", rows_done, rows, rows_done as f64 / secs.max(0.001)));
+    }
+    conn.execute_batch("COMMIT")?;
+    let secs = started.elapsed().as_secs_f64();
+    info!("Updated {} rows of {} in {:.1}s ({:.0} rows/s)", updated, table, secs, f64::from(updated) / secs.max(0.001));
+    Ok(updated)
+}
+
+// Reads the distinct strings in the columns of `info` that `column_sql` says
+// to anonymize as plain text or urls, and works out their replacements on
+// `threads` threads, so the update that follows barely has to.
+fn precompute_replacements<F>(conn: &Connection, info: &TableInfo, column_sql: F, excluded: Option<&str>,
+                              anonymizer: &Rc<RefCell<StringAnonymizer>>, threads: usize) -> Result<()>
+    where F: Fn(&str, ColumnClass) -> Option<String>
+{
+    let mut anonymizer = anonymizer.borrow_mut();
+    let mut strings = vec![];
+    for (col, &class) in info.cols.iter().zip(&info.classes) {
+        let is_url = match column_sql(col, class) {
+            Some(ref sql) if *sql == format!("anonymize({})", col) => false,
+            Some(ref sql) if *sql == format!("anonymize_opaque_url({})", col) => true,
+            _ => continue,
+        };
+        let mut stmt = conn.prepare(&format!("SELECT DISTINCT {0} FROM {1} WHERE typeof({0}) = 'text'{2}",
+                                             col, info.name, excluded.unwrap_or("")))?;
+        for text in stmt.query_map(&[], |row| row.get::<_, String>(0))? {
+            let text = text?;
+            // These are kept, or only partly anonymized.
+            if is_url && (anonymizer.is_kept_url(&text) || anonymizer.is_internal_url(&text)) {
+                continue;
+            }
+            strings.push(text);
+        }
+    }
+    if !strings.is_empty() {
+        debug!("Precomputing replacements for {} strings in {} on {} threads", strings.len(), info.name, threads);
+        anonymizer.precompute(strings, threads);
+    }
+    Ok(())
+}
+
+// Registers `f` as a one argument SQL function that's applied to text, and
+// returns anything else as is.
+fn register_text_fn<F>(conn: &Connection, name: &str, anonymizer: &Rc<RefCell<StringAnonymizer>>, f: F)
+    -> Result<()> where F: Fn(&mut StringAnonymizer, &str) -> String + 'static
+{
+    let anonymizer = anonymizer.clone();
+    conn.create_scalar_function(name, 1, true, move |ctx| {
+        let arg = ctx.get::<rusqlite::types::Value>(0)?;
+        Ok(match arg {
+            rusqlite::types::Value::Text(s) =>
+                rusqlite::types::Value::Text(f(&mut anonymizer.borrow_mut(), &s)),
+            not_text => not_text
+        })
+    })?;
+    Ok(())
+}
+
+// Like `register_text_fn`, for a two argument SQL function whose second
+// argument is the kind of value (see `value_kind`).
+fn register_kind_fn<F>(conn: &Connection, name: &str, anonymizer: &Rc<RefCell<StringAnonymizer>>, f: F)
+    -> Result<()> where F: Fn(&mut StringAnonymizer, &str, &str) -> String + 'static
+{
+    let anonymizer = anonymizer.clone();
+    conn.create_scalar_function(name, 2, true, move |ctx| {
+        let arg = ctx.get::<rusqlite::types::Value>(0)?;
+        let kind = ctx.get::<String>(1)?;
+        Ok(match arg {
+            rusqlite::types::Value::Text(ref s) if !s.is_empty() =>
+                rusqlite::types::Value::Text(f(&mut anonymizer.borrow_mut(), s, &kind)),
+            not_text => not_text
+        })
+    })?;
+    Ok(())
+}
+
+// An SQL expression that's true when `col` is one of `hosts` or a subdomain
+// of one.
+fn host_condition(col: &str, hosts: &[String]) -> String {
+    hosts.iter()
+        .map(|host| {
+            let host = host.to_lowercase().replace('\'', "''");
+            format!("{0} = '{1}' OR substr({0}, -{2}) = '.{1}'", col, host, host.len() + 1)
+        })
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+// Whether `options` select `table` to be anonymized.
+fn table_selected(options: &Options, table: &str) -> bool {
+    options.tables.as_ref().map(|tables| tables.iter().any(|t| t == table)).unwrap_or(true)
+        && !options.exclude_tables.iter().any(|t| t == table)
+}
+
+// A random number generator for anything other than strings. Determined by
+// `seed` if there is one, so seeded runs stay reproducible.
+fn make_rng(seed: Option<u64>, secure: bool) -> Result<StdRng> {
+    Ok(match seed {
+        Some(seed) => {
+            let mut bytes = <StdRng as SeedableRng>::Seed::default();
+            bytes[..8].copy_from_slice(&seed.to_le_bytes());
+            StdRng::from_seed(bytes)
+        }
+        None if secure => StdRng::from_rng(OsRng::new()?)?,
+        None => StdRng::from_entropy(),
+    })
+}
+
+// A random number of seconds, up to a year either way but never none, to
+// shift dates by.
+fn random_date_shift(seed: Option<u64>, secure: bool) -> Result<i64> {
+    const YEAR: i64 = 365 * 24 * 60 * 60;
+    let mut rng = make_rng(seed, secure)?;
+    let shift = i64::from(rng.gen::<u32>()) % (2 * YEAR) - YEAR;
+    Ok(if shift == 0 { YEAR } else { shift })
+}
+
+// The SQL function (registered by `anonymize_places`) that anonymizes a column
+// of the given class. Hosts are anonymized label by label, and reversed hosts
+// so they stay the reverse of the anonymized host, so that Firefox's lookups by
+// host still work. JSON keeps its structure and keys. Numbers pass through
+// `anonymize` unchanged, but it still catches any text stored in them.
+fn column_function(class: ColumnClass, options: &Options) -> &'static str {
+    match class {
+        ColumnClass::Host => "anonymize_host",
+        ColumnClass::RevHost => "anonymize_rev_host",
+        ColumnClass::Json => "anonymize_json",
+        ColumnClass::Url if options.keep_url_structure => "anonymize_url",
+        ColumnClass::Url => "anonymize_opaque_url",
+        // Origin prefixes keep their scheme, so they're still valid, and with
+        // --keep-url-structure each url still starts with its origin's prefix.
+        ColumnClass::Prefix => "anonymize_prefix",
+        ColumnClass::Title if options.keep_title_structure => "anonymize_words",
+        ColumnClass::Path => "anonymize_path",
+        ColumnClass::Origin => "anonymize_origin",
+        ColumnClass::OriginKey => "anonymize_origin_key",
+        ColumnClass::Guid if options.regenerate_guids => "regenerate_guid",
+        ColumnClass::Guid => "anonymize_guid",
+        _ => "anonymize",
+    }
+}
+
+// The kind of value a column of the given class holds, for modes that replace
+// each kind differently, or None for those that are anonymized as usual in
+// every mode.
+fn value_kind(class: ColumnClass) -> Option<&'static str> {
+    match class {
+        ColumnClass::Url => Some("url"),
+        ColumnClass::Host => Some("host"),
+        ColumnClass::RevHost => Some("rev_host"),
+        ColumnClass::Json => Some("json"),
+        ColumnClass::Title => Some("title"),
+        // These must stay valid, and don't say much anyway.
+        ColumnClass::Prefix | ColumnClass::Guid => None,
+        // Anonymized piece by piece, so still a path or an origin.
+        ColumnClass::Path | ColumnClass::Origin | ColumnClass::OriginKey => None,
+        ColumnClass::Timestamp | ColumnClass::Numeric | ColumnClass::Text => Some("text"),
+    }
+}
+
+// The SQL that replaces `col`, given its class, and whether it's in a UNIQUE
+// index (so needs to stay unique).
+fn column_expr(col: &str, class: ColumnClass, unique: bool, options: &Options) -> String {
+    match (options.mode, value_kind(class)) {
+        (Mode::Redact, Some(kind)) if unique => format!("redact_unique({}, '{}')", col, kind),
+        (Mode::Redact, Some(kind)) => format!("redact({}, '{}')", col, kind),
+        (Mode::Sequential, Some(kind)) => format!("sequential({}, '{}')", col, kind),
+        (Mode::Hash, Some(kind)) => format!("hash_token({}, '{}')", col, kind),
+        (Mode::Fake, Some(kind)) => format!("fake({}, '{}')", col, kind),
+        (Mode::Stream, Some(kind)) => format!("stream({}, '{}')", col, kind),
+        (Mode::Stream, None) if class == ColumnClass::Guid && !options.regenerate_guids =>
+            format!("stream({}, 'guid')", col),
+        _ => format!("{}({})", column_function(class, options), col),
+    }
+}
+
+// Whether `column_expr` replaces a column of `class` without remembering
+// anything, so without checking what it's already issued.
+fn is_stateless(class: ColumnClass, options: &Options) -> bool {
+    match (options.mode, value_kind(class)) {
+        (Mode::Hash, Some(_)) | (Mode::Stream, Some(_)) => true,
+        (Mode::Stream, None) => class == ColumnClass::Guid && !options.regenerate_guids,
+        _ => false,
+    }
+}
+
+// The columns of `info` that are anonymized as text. Numbers and dates are in
+// the update too, to catch any text in them, but it leaves their numbers as
+// they are.
+fn text_columns<'a>(info: &'a TableInfo, options: &Options) -> Vec<&'a str> {
+    info.cols.iter().zip(&info.classes)
+        .filter(|&(_, &class)| class != ColumnClass::Numeric && class != ColumnClass::Timestamp)
+        .filter(|&(col, _)| options.policies.for_column(&info.name, col) != Some(Policy::Keep))
+        .map(|(col, _)| col.as_str())
+        .collect()
+}
+
+// Like `column_expr`, but anonymizes `text` directly, for urls and titles that
+// aren't in a database.
+fn anonymize_text(anonymizer: &mut StringAnonymizer, class: ColumnClass, text: &str, options: &Options) -> String {
+    match (options.mode, value_kind(class)) {
+        (Mode::Redact, Some(kind)) => StringAnonymizer::redacted(kind).into(),
+        (Mode::Sequential, Some(kind)) => anonymizer.sequential(text, kind),
+        (Mode::Hash, Some(kind)) => anonymizer.hash_token(text, kind),
+        (Mode::Fake, Some(kind)) => anonymizer.fake(text, kind),
+        (Mode::Stream, Some(kind)) => anonymizer.stream(text, kind),
+        _ => match class {
+            ColumnClass::Url if options.keep_url_structure => anonymizer.anonymize_url(text),
+            ColumnClass::Url => anonymizer.anonymize_opaque_url(text),
+            ColumnClass::Title if options.keep_title_structure => anonymizer.anonymize_words(text),
+            _ => anonymizer.anonymize(text),
+        },
+    }
+}
+
+/// Writes an anonymized copy of the places database at `input` (which may be
+/// mozlz4 or zstd compressed) to `output`, overwriting whatever is there.
+/// `input` itself is never modified.
+pub fn anonymize_places(input: &Path, output: &Path, options: &Options) -> Result<Outcome> {
+    check_options(options)?;
+    let options = &apply_scrub_level(options);
+    check_output(input, output, options)?;
+    anonymize_database(input, output, options, &make_anonymizer(options)?, !options.generic)
+}
+
+/// Anonymizes several databases with the same replacements, so that e.g. a
+/// host in places.sqlite gets the same replacement in favicons.sqlite, and
+/// the databases still refer to each other.
+pub struct Session {
+    options: Options,
+    anonymizer: Rc<RefCell<StringAnonymizer>>,
+}
+
+impl Session {
+    pub fn new(options: Options) -> Result<Session> {
+        check_options(&options)?;
+        let options = apply_scrub_level(&options);
+        let anonymizer = make_anonymizer(&options)?;
+        Ok(Session { options, anonymizer })
+    }
+
+    /// Like [`anonymize_places`], but `input` can be any of Firefox's
+    /// databases. Those without places tables are anonymized generically.
+    /// It can also be a session store (like `sessionstore.jsonlz4`), whose
+    /// tabs' urls and titles are anonymized, and whose form data, session
+    /// storage and cookies are removed. With `export_mapping`, the mapping is
+    /// rewritten after each input to include everything so far.
+    pub fn anonymize(&self, input: &Path, output: &Path) -> Result<Outcome> {
+        check_output(input, output, &self.options)?;
+        match SessionStore::read(input)? {
+            Some(store) => self.anonymize_session_store(store, output),
+            None => anonymize_database(input, output, &self.options, &self.anonymizer, false),
+        }
+    }
+
+    fn anonymize_session_store(&self, mut store: SessionStore, output: &Path) -> Result<Outcome> {
+        let options = &self.options;
+        let mut anonymizer = self.anonymizer.borrow_mut();
+        let count = store.anonymize(|class, text| anonymize_text(&mut anonymizer, class, text, options));
+        if options.dry_run {
+            info!("Would anonymize {} urls and titles in the session store", count);
+            return Ok(Outcome::DryRun(DryRun::default()));
+        }
+        info!("Anonymized {} urls and titles in the session store", count);
+        store.write(output)?;
+        if let (Some(path), Some(passphrase)) = (&options.export_mapping, &options.mapping_passphrase) {
+            info!("Writing the encrypted mapping to {:?}", path);
+            write_mapping(path, &anonymizer.mapping(), passphrase)?;
+        }
+        Ok(Outcome::Anonymized { schema_version: 0, tables: vec![] })
+    }
+}
+
+fn check_options(options: &Options) -> Result<()> {
+    if options.scrub_level > 5 {
+        bail!(BadOptions, "Bad scrub level {}, expected 0 to 5", options.scrub_level);
+    }
+    if options.scrub_level >= 5 && options.fingerprint_salt.is_some() {
+        bail!(BadOptions, "Fingerprint salt can't be used with scrub level 5");
+    }
+    if options.scrub_level >= 5 && options.key.is_some() {
+        bail!(BadOptions, "Keyed anonymization can't be used with scrub level 5");
+    }
+    if let Some(noise) = options.count_noise {
+        if !(noise > 0.0 && noise < 100.0) {
+            bail!(BadOptions, "Bad count noise {}, expected more than 0 and less than 100 percent", noise);
+        }
+    }
+    if options.secure_rng && (options.seed.is_some() || options.key.is_some()) {
+        bail!(BadOptions, "A secure RNG can't be used with a seed or key, which make replacements reproducible");
+    }
+    if (options.export_mapping.is_some() || options.import_mapping.is_some()) && options.mapping_passphrase.is_none() {
+        bail!(BadOptions, "Exporting or importing a mapping needs a passphrase to encrypt or decrypt it with");
+    }
+    if options.length_bucket == Some(0) {
+        bail!(BadOptions, "Can't bucket lengths by 0 characters");
+    }
+    if options.round_dates.map(|d| d.as_millis() == 0).unwrap_or(false) {
+        bail!(BadOptions, "Can't round dates to less than a millisecond");
+    }
+    if options.min_size_reduction.is_some() && !options.vacuum {
+        bail!(BadOptions, "Checking how much smaller the output is needs it vacuumed");
+    }
+    if let Some(ref path) = options.export_mapping {
+        check_not_in_profile(path)?;
+    }
+    Ok(())
+}
+
+// Turns on the options `options.scrub_level` implies, as documented on
+// `Options::scrub_level`. The counts and dates it scrubs without an option of
+// their own are left to `anonymize_database`.
+fn apply_scrub_level(options: &Options) -> Options {
+    let mut options = options.clone();
+    let level = options.scrub_level;
+    if level == 0 {
+        options.keep_public_suffix = true;
+        options.keep_query_keys = true;
+        options.keep_title_structure = true;
+        options.anonymize_downloads = true;
+    }
+    if level <= 1 {
+        options.keep_url_structure = true;
+    }
+    if level >= 4 && options.round_dates.is_none() {
+        options.round_dates = Some(Duration::from_secs(24 * 60 * 60));
+    }
+    if level >= 5 {
+        options.regenerate_guids = true;
+        options.strip_query = true;
+        options.strip_favicons = true;
+        options.length_bucket = options.length_bucket.or(Some(16));
+    }
+    options
+}
+
+// Refuses to write `output` over `input`, or anywhere in a browser profile,
+// where we could clobber (or confuse the browser with) someone's real data.
+// We only ever read the input.
+fn check_output(input: &Path, output: &Path, options: &Options) -> Result<()> {
+    if options.dry_run {
+        return Ok(());
+    }
+    let input = fs::canonicalize(input).unwrap_or_else(|_| input.to_owned());
+    let output_in = fs::canonicalize(parent_dir(output))
+        .map(|dir| dir.join(output.file_name().unwrap_or_default()))
+        .unwrap_or_else(|_| output.to_owned());
+    if output_in == input {
+        bail!(UnsafeOutput, "{:?} is the input; the output has to go somewhere else", output);
+    }
+    check_not_in_profile(output)
+}
+
+fn check_not_in_profile(path: &Path) -> Result<()> {
+    let dir = parent_dir(path);
+    if is_profile_dir(dir) {
+        bail!(UnsafeOutput, "{:?} is in a browser profile, which we never write to; write it somewhere else", path);
+    }
+    Ok(())
+}
+
+// The directory `path` is in, which is the current one for a bare file name.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+// The anonymizer the options call for, with any mapping they say to reuse.
+fn make_anonymizer(options: &Options) -> Result<Rc<RefCell<StringAnonymizer>>> {
+    let anonymizer = Rc::new(RefCell::new(match (&options.key, options.seed) {
+        (Some(key), _) => StringAnonymizer::with_key(key),
+        (None, Some(seed)) => StringAnonymizer::with_seed(seed),
+        (None, None) if options.threads > 1 => StringAnonymizer::with_random_key(make_rng(None, options.secure_rng)?),
+        (None, None) if options.secure_rng => StringAnonymizer::secure()?,
+        (None, None) => StringAnonymizer::default(),
+    }));
+    anonymizer.borrow_mut().keep_hosts(options.keep_hosts.iter().cloned());
+    if options.keep_public_suffix {
+        anonymizer.borrow_mut().keep_public_suffixes();
+    }
+    if options.keep_query_keys {
+        anonymizer.borrow_mut().keep_query_keys();
+    }
+    if let Some(budget) = options.memory_budget {
+        anonymizer.borrow_mut().limit_memory(budget);
+    }
+    if let Some(bucket) = options.length_bucket {
+        anonymizer.borrow_mut().bucket_lengths(bucket);
+    }
+    if let (Some(path), Some(passphrase)) = (&options.import_mapping, &options.mapping_passphrase) {
+        info!("Reusing the replacements in {:?}", path);
+        anonymizer.borrow_mut().load_mapping(&read_mapping(path, passphrase)?);
+    }
+    Ok(anonymizer)
+}
+
+// Does the work of `anonymize_places` and `Session::anonymize`. Unless
+// `places_only`, databases without places tables are anonymized generically
+// rather than refused.
+fn anonymize_database(input: &Path, output: &Path, options: &Options,
+                      anonymizer: &Rc<RefCell<StringAnonymizer>>, places_only: bool) -> Result<Outcome> {
+
+    let progress = Progress::new(options.progress, options.cancel.clone());
+    progress.phase(format!("Copying {}", input.display()));
+    // Everything after this works on a private copy of the input, so we never
+    // see a half written database, or read the input more than once. (If it's
+    // compressed, it'll be bigger than this, but it's a start.)
+    let size = |path: &Path| fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+    check_free_space(&std::env::temp_dir(), size(input) + size(&sidecar(input, "-wal")), "copying the input")?;
+    let mut source = match decompress_source(input)? {
+        Some(decompressed) => decompressed,
+        None => snapshot(input)?,
+    };
+    // Anonymizing a corrupt database would hide that it was corrupt to
+    // begin with from whoever we hand it to, unless we say so.
+    progress.phase(format!("Checking {}", input.display()));
+    if let Err(e) = check_integrity(source.path(), options.thorough_verify, &format!("The input {:?}", input)) {
+        if !options.salvage {
+            bail!(Corrupt, "{}. --salvage anonymizes what can still be read of it", e);
+        }
+        warn!("{}, so salvaging what we can of it", e);
+        progress.phase(format!("Salvaging {}", input.display()));
+        source = salvage(source.path())?;
+        check_integrity(source.path(), options.thorough_verify, "The salvaged copy of the input")?;
+    }
+    let source_path = source.path();
+    let input_size = fs::metadata(source_path)?.len();
+
+    let (version, has_places, is_fenix, other_database) = {
+        let source = Connection::open_with_flags(source_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut stmt = source.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+        let names = stmt.query_map(&[], |row| row.get::<_, String>(0))?
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        let other_database = OTHER_DATABASES.iter()
+            .find(|&&(_, table)| names.iter().any(|name| name == table))
+            .map(|&(kind, _)| kind);
+        let is_fenix = names.iter().any(|name| name == "moz_bookmarks_synced");
+        (schema_version(&source)?, names.iter().any(|name| name == "moz_places"), is_fenix, other_database)
+    };
+    if let (Some(wanted), true) = (options.only_schema_version, has_places) {
+        if version != wanted {
+            return Ok(Outcome::Skipped { schema_version: version });
+        }
+    }
+    if options.generic {
+        info!("Anonymizing {:?} generically", input);
+    } else if let (false, Some(kind)) = (has_places, other_database) {
+        info!("{:?} looks like {}, anonymizing it", input, kind);
+    } else if !has_places && !places_only {
+        info!("{:?} isn't a places database, anonymizing it generically", input);
+    } else if is_fenix {
+        // Its schema versions are numbered separately from Firefox's.
+        info!("{:?} is Firefox for Android's places database (schema version {})", input, version);
+    } else if version == 0 || !has_places {
+        bail!(SchemaUnsupported, "{:?} doesn't look like a places database (it has no schema version or moz_places)", input);
+    } else if version < OLDEST_KNOWN_SCHEMA {
+        bail!(SchemaUnsupported, "Places schema version {} is older than any we support (the oldest is {})",
+              version, OLDEST_KNOWN_SCHEMA);
+    } else if version > NEWEST_KNOWN_SCHEMA {
+        if !options.allow_unknown_schema_version {
+            bail!(SchemaUnsupported, "Places schema version {} is newer than any we know about (the newest is {}), \
+                   so it may have data we don't know to anonymize", version, NEWEST_KNOWN_SCHEMA);
+        }
+        warn!("Places schema version {} is newer than any we know about, anonymizing it generically",
+              version);
+    }
+    debug!("Places schema version {}", version);
+
+    // We anonymize a copy in a temporary file next to `output`, which only
+    // replaces it once everything (including the checks) has succeeded, so a
+    // failed or killed run never leaves a partly anonymized database there.
+    // Otherwise the file is removed when this returns.
+    let partial = if options.dry_run {
+        None
+    } else {
+        let dir = parent_dir(output);
+        // With --safe-writes, the rollback journal can grow as big again.
+        let needed = if options.fast_writes { input_size } else { 2 * input_size };
+        check_free_space(dir, needed, "the output")?;
+        let partial = tempfile::Builder::new().prefix(".anonymize-places-").suffix(".sqlite").tempfile_in(dir)
+            .map_err(|e| format_err!(Other, "Can't create a temporary file in {:?}: {}", dir, e))?;
+        progress.phase(format!("Copying to {}", output.display()));
+        fs::copy(source_path, partial.path())?;
+        Some(partial)
+    };
+    // A dry run reads our copy of the input instead, and only collects the
+    // SQL that would run on the output.
+    let anon_path = partial.as_ref().map_or(source_path, |partial| partial.path());
+    let anon_places = if options.dry_run {
+        Connection::open_with_flags(source_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?
+    } else {
+        Connection::open_with_flags(anon_path, OpenFlags::SQLITE_OPEN_READ_WRITE)?
+    };
+    let journal_mode: String = anon_places.query_row("PRAGMA journal_mode", &[], |row| row.get(0))?;
+    let fast_writes = options.fast_writes && !options.dry_run;
+    if fast_writes {
+        // Syncing every statement to disk dominates the time it takes to
+        // anonymize a big database.
+        anon_places.execute_batch("
+            PRAGMA journal_mode = MEMORY;
+            PRAGMA synchronous = OFF;
+            PRAGMA cache_size = -65536;
+            PRAGMA temp_store = MEMORY;
+        ")?;
+    }
+    if !options.vacuum {
+        // Otherwise what we delete or overwrite stays in the file's free
+        // space until VACUUM would have rewritten it. This does nothing for
+        // the free space the input already had.
+        anon_places.execute_batch("PRAGMA secure_delete = ON")?;
+    }
+    let watching = progress.watch(&anon_places);
+    let dry_run_sql = RefCell::new(vec![]);
+    let execute = |sql: &str| -> Result<i32> {
+        if options.dry_run {
+            dry_run_sql.borrow_mut().push(sql.trim().to_owned());
+            return Ok(0);
+        }
+        debug!("Executing sql:\n{}", sql);
+        Ok(anon_places.execute(sql, &[])?)
+    };
+
+    // The anonymizing functions only touch text. NULLs (and numbers and blobs)
+    // are returned as is, so which values were NULL is preserved.
+    register_text_fn(&anon_places, "anonymize", anonymizer, StringAnonymizer::anonymize)?;
+    register_text_fn(&anon_places, "anonymize_exact", anonymizer, StringAnonymizer::anonymize_exact)?;
+    register_text_fn(&anon_places, "anonymize_guid", anonymizer, StringAnonymizer::anonymize_guid)?;
+    register_text_fn(&anon_places, "anonymize_path", anonymizer, StringAnonymizer::anonymize_path)?;
+    register_text_fn(&anon_places, "anonymize_host", anonymizer, StringAnonymizer::anonymize_host)?;
+    register_text_fn(&anon_places, "anonymize_rev_host", anonymizer, StringAnonymizer::anonymize_rev_host)?;
+    register_text_fn(&anon_places, "anonymize_url", anonymizer, StringAnonymizer::anonymize_url)?;
+    register_text_fn(&anon_places, "anonymize_opaque_url", anonymizer, StringAnonymizer::anonymize_opaque_url)?;
+    register_text_fn(&anon_places, "anonymize_origin", anonymizer, StringAnonymizer::anonymize_origin)?;
+    register_text_fn(&anon_places, "anonymize_origin_key", anonymizer, StringAnonymizer::anonymize_origin_key)?;
+    register_text_fn(&anon_places, "placeholder", anonymizer, |_, s| "x".repeat(s.chars().count()))?;
+    register_text_fn(&anon_places, "anonymize_prefix", anonymizer, StringAnonymizer::anonymize_prefix)?;
+    register_text_fn(&anon_places, "anonymize_json", anonymizer, StringAnonymizer::anonymize_json)?;
+    register_text_fn(&anon_places, "anonymize_words", anonymizer, StringAnonymizer::anonymize_words)?;
+    register_text_fn(&anon_places, "regenerate_guid", anonymizer, StringAnonymizer::regenerate_guid)?;
+    register_text_fn(&anon_places, "strip_query", anonymizer, |_, url| strip_query(url))?;
+    register_kind_fn(&anon_places, "redact", anonymizer, |_, _, kind| StringAnonymizer::redacted(kind).into())?;
+    register_kind_fn(&anon_places, "redact_unique", anonymizer, StringAnonymizer::redact_unique)?;
+    register_kind_fn(&anon_places, "sequential", anonymizer, StringAnonymizer::sequential)?;
+    register_kind_fn(&anon_places, "hash_token", anonymizer, StringAnonymizer::hash_token)?;
+    register_kind_fn(&anon_places, "fake", anonymizer, StringAnonymizer::fake)?;
+    register_kind_fn(&anon_places, "stream", anonymizer, StringAnonymizer::stream)?;
+    // Named like the function Firefox registers, so the same SQL works in both.
+    anon_places.create_scalar_function("hash", 1, true, |ctx| {
+        let url = ctx.get::<Option<String>>(0)?;
+        Ok(url.map(|url| hash_url(&url) as i64))
+    })?;
+    anon_places.create_scalar_function("fixup_url", 1, true, |ctx| {
+        let url = ctx.get::<Option<String>>(0)?;
+        Ok(url.map(|url| fixup_url(&url).to_owned()))
+    })?;
+
+    if let Some(noise) = options.count_noise {
+        // Seeded differently from the date shift, which would otherwise pick
+        // the same first number.
+        let mut rng = make_rng(options.seed.map(|seed| !seed), options.secure_rng)?;
+        anon_places.create_scalar_function("add_noise", 1, false, move |ctx| {
+            Ok(match ctx.get::<rusqlite::types::Value>(0)? {
+                rusqlite::types::Value::Integer(n) if n > 0 => {
+                    let r = f64::from(rng.gen::<u32>()) / f64::from(u32::MAX);
+                    let factor = 1.0 + noise / 100.0 * (2.0 * r - 1.0);
+                    rusqlite::types::Value::Integer(((n as f64 * factor).round() as i64).max(1))
+                }
+                other => other,
+            })
+        })?;
+    }
+
+    let all_tables = read_tables(&anon_places, options.generic)?;
+    let missing = KNOWN_TABLES.iter().cloned()
+        .filter(|&name| !all_tables.iter().any(|info| info.name == name))
+        .collect::<Vec<_>>();
+    if has_places && !is_fenix && !options.generic && !missing.is_empty() {
+        info!("Tables this schema doesn't have, skipping: {}", missing.join(", "));
+    }
+    for name in options.tables.iter().flatten().chain(&options.exclude_tables) {
+        if !all_tables.iter().any(|info| &info.name == name) {
+            warn!("No {} table to select or exclude", name);
+        }
+    }
+    // Everything from here on only touches the selected tables. The rest are
+    // copied as is.
+    let (schema, left_as_is): (Vec<_>, Vec<_>) = all_tables.iter().cloned()
+        .partition(|info| table_selected(options, &info.name));
+    if !left_as_is.is_empty() {
+        warn!("Leaving these tables as they are, without anonymizing them: {}",
+              left_as_is.iter().map(|info| info.name.as_str()).collect::<Vec<_>>().join(", "));
+    }
+    let has_table = |name: &str| schema.iter().any(|info| info.name == name);
+
+    // Tables are updated row by row, so a replacement in a UNIQUE column (like
+    // a keyword, or an annotation name) mustn't be a value that's yet to be
+    // anonymized. Columns replaced without remembering anything never look.
+    for info in &schema {
+        for col in &info.unique {
+            let class = info.cols.iter().zip(&info.classes).find(|&(c, _)| c == col).map(|(_, &class)| class);
+            if class.is_some_and(|class| is_stateless(class, options)) {
+                continue;
+            }
+            let mut stmt = anon_places.prepare(&format!(
+                "SELECT {0} FROM {1} WHERE typeof({0}) = 'text'", col, info.name))?;
+            let is_host = info.cols_of(ColumnClass::Host).any(|c| c == col);
+            for value in stmt.query_map(&[], |row| row.get::<_, String>(0))? {
+                let value = value?;
+                if is_host {
+                    // Hosts (like moz_hosts') are anonymized label by label.
+                    anonymizer.borrow_mut().avoid_host(&value);
+                } else {
+                    anonymizer.borrow_mut().avoid(&value);
+                }
+            }
+        }
+    }
+
+    // Triggers (like Firefox's on moz_places) would fire for every row we
+    // update or delete, working things out from half anonymized data and
+    // slowing us down, so they're dropped before we write anything and put
+    // back at the end.
+    let triggers = {
+        let mut stmt = anon_places.prepare("SELECT name, sql FROM sqlite_master WHERE type = 'trigger' AND sql IS NOT NULL")?;
+        let rows = stmt.query_map(&[], |row| (row.get::<_, String>(0), row.get::<_, String>(1)))?;
+        rows.collect::<::std::result::Result<Vec<_>, _>>()?
+    };
+    for (name, _) in &triggers {
+        debug!("Dropping trigger {} until we're done", name);
+        execute(&format!("DROP TRIGGER {}", name))?;
+    }
+
+    let mut exclusions = Exclusions::default();
+    // The built-in bookmark roots have well-known guids and titles that
+    // Firefox relies on to load the bookmark tree.
+    exclusions.add("moz_bookmarks", format!("guid IN {}", BOOKMARK_ROOT_GUIDS));
+    exclusions.add("moz_bookmarks_roots", "1".into());
+    exclusions.add("moz_bookmarks_synced", format!("guid IN {}", BOOKMARK_ROOT_GUIDS));
+    if !options.keep_hosts.is_empty() {
+        // Origins (and old style hosts) of kept hosts are left whole, so their
+        // prefixes stay as they are too.
+        for &table in &["moz_origins", "moz_hosts"] {
+            exclusions.add(table, host_condition("host", &options.keep_hosts));
+        }
+    }
+    // Downloads have been stored as annotations since Firefox 26.
+    let has_download_annos = has_table("moz_annos") && has_table("moz_anno_attributes");
+    if options.anonymize_downloads && !has_download_annos {
+        info!("No annotation tables, so no download annotations to keep");
+    }
+    if options.anonymize_downloads && has_download_annos {
+        // Keep the download annotation names (Firefox looks them up by name)
+        // and the metadata JSON (just state, times and sizes), and anonymize
+        // the destination path segment by segment.
+        exclusions.add("moz_anno_attributes", format!("name IN {}", DOWNLOAD_ANNOS));
+        exclusions.add("moz_annos", format!("
+            anno_attribute_id IN (SELECT id FROM moz_anno_attributes WHERE name IN {})
+        ", DOWNLOAD_ANNOS));
+        debug!("Anonymizing download destinations");
+        execute("
+            UPDATE moz_annos SET content = anonymize_path(content)
+            WHERE anno_attribute_id IN (
+                SELECT id FROM moz_anno_attributes WHERE name = 'downloads/destinationFileURI'
+            )
+        ")?;
+    }
+
+    if has_table("prefs") && has_table("settings") {
+        debug!("Anonymizing paths in content prefs");
+        execute(&format!("
+            UPDATE prefs SET value = anonymize_path(value)
+            WHERE settingID IN (SELECT id FROM settings WHERE name IN {})
+        ", PATH_CONTENT_PREFS))?;
+    }
+
+    let unhandled = schema.iter()
+        .map(|info| info.name.as_str())
+        .filter(|name| !KNOWN_TABLES.contains(name) && !FENIX_TABLES.contains(name) && !OTHER_KNOWN_TABLES.contains(name))
+        .filter(|name| !options.allowed_tables.iter().any(|t| t == name))
+        // A table with its own policy is handled, whatever it is.
+        .filter(|name| options.policies.for_table(name).is_none())
+        .collect::<Vec<_>>();
+    if !unhandled.is_empty() && !options.generic {
+        if options.strict {
+            bail!(SchemaUnsupported, "Found tables we don't know how to handle: {}", unhandled.join(", "));
+        }
+        warn!("Found unknown tables (possibly from an extension), anonymizing them generically: {}",
+              unhandled.join(", "));
+    }
+
+    for (table, col) in options.policies.named() {
+        match all_tables.iter().find(|info| info.name == table) {
+            None => warn!("The policies name table {}, which this database doesn't have", table),
+            Some(info) => if let Some(col) = col {
+                if !info.cols.iter().any(|c| c == col) {
+                    warn!("The policies name column {}.{}, which this database doesn't have", table, col);
+                }
+            },
+        }
+    }
+    // Generic anonymization goes only by the user's policies.
+    let policies = if options.generic { options.policies.clone() } else { options.policies.with_builtin() };
+    let options = &Options { policies, ..options.clone() };
+    let mut plans = vec![];
+    let mut deletions = HashMap::new();
+    for info in &all_tables {
+        let mut deleted_rows = 0;
+        if let Some(cond) = options.policies.deletion(&info.name) {
+            if options.dry_run {
+                deleted_rows = anon_places.query_row(&format!("SELECT count(*) FROM {} WHERE {}", info.name, cond),
+                                                     &[], |row| row.get(0))?;
+            }
+            let deleted = execute(&format!("DELETE FROM {} WHERE {}", info.name, cond))?;
+            if !options.dry_run {
+                info!("Deleted {} row(s) from {} as the policies say", deleted, info.name);
+                deletions.insert(info.name.as_str(), i64::from(deleted));
+            }
+            exclusions.add(&info.name, cond);
+        }
+        if options.dry_run {
+            let left_as_is = !table_selected(options, &info.name);
+            plans.push(TablePlan {
+                name: info.name.clone(),
+                rows: anon_places.query_row(&format!("SELECT count(*) FROM {}", info.name), &[], |row| row.get(0))?,
+                deleted_rows,
+                left_as_is,
+                columns: if left_as_is { vec![] } else { text_columns(info, options).into_iter().map(String::from).collect() },
+            });
+        }
+    }
+
+    if let Some(ref salt) = options.fingerprint_salt {
+        info!("Recording salted url fingerprints in url_fingerprints");
+        let mac = Hmac::<Sha256>::new_from_slice(salt.as_bytes())
+            .map_err(|e| format_err!(BadOptions, "Bad fingerprint salt: {}", e))?;
+        anon_places.create_scalar_function("url_fingerprint", 1, true, move |ctx| {
+            let url = ctx.get::<String>(0)?;
+            Ok(url_fingerprint(&mac, &url))
+        })?;
+        execute("
+            CREATE TABLE url_fingerprints (
+                place_id INTEGER PRIMARY KEY,
+                url_fingerprint TEXT NOT NULL
+            )
+        ")?;
+        execute("
+            INSERT INTO url_fingerprints (place_id, url_fingerprint)
+            SELECT id, url_fingerprint(url) FROM moz_places WHERE url IS NOT NULL
+        ")?;
+    }
+
+    let scrub_level = options.scrub_level;
+    // Firefox for Android's dates are all in milliseconds.
+    let (date_columns, ms_date_columns) = if is_fenix {
+        (&[][..], FENIX_MS_DATE_COLUMNS)
+    } else {
+        (DATE_COLUMNS, MS_DATE_COLUMNS)
+    };
+    let started = Instant::now();
+    let watchdog = options.max_runtime.map(|budget| Watchdog::start(&anon_places, budget));
+    let mut tables_done = 0;
+    let mut stats = vec![];
+    let result = (|| -> Result<()> {
+        if options.strip_query {
+            for info in &schema {
+                for col in info.cols_of(ColumnClass::Url) {
+                    if options.policies.for_column(&info.name, col).is_some() {
+                        continue;
+                    }
+                    // Urls that only differ in their query would collide in a
+                    // UNIQUE column (like moz_places.url in old schemas), so
+                    // those keep it. It's still anonymized with the rest.
+                    let mut sql = format!("UPDATE OR IGNORE {0} SET {1} = strip_query({1})", info.name, col);
+                    if let Some(cond) = exclusions.condition(&info.name) {
+                        sql.push_str(&format!(" WHERE NOT ({})", cond));
+                    }
+                    execute(&sql)?;
+                }
+            }
+        }
+        for (i, info) in schema.iter().enumerate() {
+            progress.phase(format!("Anonymizing {} ({} of {} tables)", info.name, i + 1, schema.len()));
+            // Strings in more than one of the columns count once.
+            let excluded = exclusions.condition(&info.name).map(|cond| format!(" AND NOT ({})", cond));
+            let distinct_strings = match text_columns(info, options) {
+                ref cols if options.stats && !cols.is_empty() => anon_places.query_row(&format!(
+                    "SELECT count(*) FROM ({})",
+                    cols.iter()
+                        .map(|c| format!("SELECT {0} FROM {1} WHERE typeof({0}) = 'text'{2}",
+                                         c, info.name, excluded.as_deref().unwrap_or("")))
+                        .collect::<Vec<_>>().join(" UNION ")
+                ), &[], |row| row.get(0))?,
+                _ => 0,
+            };
+            // Tables without rowids can't be chunked.
+            let chunked = options.engine == Engine::Rows
+                && anon_places.prepare(&format!("SELECT rowid FROM {} LIMIT 0", info.name)).is_ok();
+            let column_sql = |col: &str, class| {
+                let unique = info.in_unique_index.iter().any(|c| c == col);
+                Some(match options.policies.for_column(&info.name, col) {
+                    Some(Policy::Keep) => return None,
+                    Some(Policy::Null) => "NULL".into(),
+                    Some(Policy::Hash) => format!("hash_token(CAST({} AS TEXT), 'text')", col),
+                    Some(Policy::Anonymize) => column_expr(col, ColumnClass::Text, unique, options),
+                    None if (info.name.as_str(), col) == COOKIE_VALUES && options.hash_cookie_values =>
+                        format!("hash_token({}, 'text')", col),
+                    None if (info.name.as_str(), col) == DOWNLOAD_HASHES =>
+                        format!("CASE WHEN {0} IS NULL THEN NULL ELSE X'' END", col),
+                    None if (info.name.as_str(), col) == LOCAL_STORAGE_VALUES => format!("placeholder({})", col),
+                    None if (info.name.as_str(), col) == COOKIE_VALUES =>
+                        format!("CASE typeof({0}) WHEN 'text' THEN '' ELSE {0} END", col),
+                    Some(Policy::DeleteRows) | None => column_expr(col, class, unique, options),
+                })
+            };
+            if options.threads > 1 && !options.dry_run {
+                precompute_replacements(&anon_places, info, column_sql, excluded.as_deref(), anonymizer, options.threads)?;
+            }
+            let sql = info.make_update(column_sql, exclusions.condition(&info.name).as_deref(), chunked);
+            let updated_rows = match sql {
+                Some(sql) if chunked && !options.dry_run =>
+                    update_in_chunks(&anon_places, &info.name, &sql, &progress, options.batch_size,
+                                     options.cancel.as_deref())?,
+                Some(sql) => execute(&sql)?,
+                None => 0,
+            };
+            if options.stats {
+                let deleted_rows = deletions.get(info.name.as_str()).cloned().unwrap_or(0);
+                stats.push(TableStats {
+                    name: info.name.clone(),
+                    rows: anon_places.query_row(&format!("SELECT count(*) FROM {}", info.name), &[], |row| row.get::<_, i64>(0))?
+                        + deleted_rows,
+                    updated_rows: i64::from(updated_rows),
+                    deleted_rows,
+                    distinct_strings,
+                });
+            }
+            tables_done += 1;
+        }
+        progress.phase("Recomputing url hashes and scrubbing".into());
+        for &(table, col, url_col, fixup) in URL_HASH_COLUMNS {
+            let present = schema.iter().any(|info| info.name == table && info.cols.iter().any(|c| c == col));
+            if !present || options.policies.for_column(table, col).is_some() {
+                continue;
+            }
+            debug!("Recomputing {}.{}", table, col);
+            let url = if fixup { format!("fixup_url({})", url_col) } else { url_col.to_owned() };
+            // A policy may have made the url NULL or a number.
+            execute(&format!("UPDATE {} SET {} = hash({}) WHERE typeof({}) = 'text'", table, col, url, url_col))?;
+        }
+        if has_table("keyword_search_terms") && options.policies.for_column("keyword_search_terms", "normalized_term").is_none() {
+            // Chrome looks search terms up by their lowercase version.
+            execute("
+                UPDATE keyword_search_terms SET normalized_term = lower(term) WHERE typeof(term) = 'text'
+            ")?;
+        }
+        if options.strip_favicons && has_table("moz_icons") && options.policies.for_column("moz_icons", "data").is_none() {
+            info!("Removing icon images");
+            execute("UPDATE moz_icons SET data = NULL")?;
+        }
+        if options.shift_dates {
+            info!("Shifting every date by the same random offset");
+            let shift = random_date_shift(options.seed, options.secure_rng)?;
+            // Zero means unset, so leave it that way.
+            scrub_columns(execute, &schema, &options.policies, date_columns,
+                          |c| format!("CASE WHEN {0} > 0 THEN {0} + {1} ELSE {0} END", c, shift * 1_000_000))?;
+            scrub_columns(execute, &schema, &options.policies, ms_date_columns,
+                          |c| format!("CASE WHEN {0} > 0 THEN {0} + {1} ELSE {0} END", c, shift * 1000))?;
+            scrub_columns(execute, &schema, &options.policies, SECONDS_DATE_COLUMNS,
+                          |c| format!("CASE WHEN {0} > 0 THEN {0} + {1} ELSE {0} END", c, shift))?;
+        }
+        if options.count_noise.is_some() {
+            scrub_columns(execute, &schema, &options.policies, COUNT_COLUMNS,
+                          |c| format!("add_noise({})", c))?;
+        }
+        if let Some(granularity) = options.round_dates {
+            let (us, ms) = (granularity.as_micros(), granularity.as_millis());
+            scrub_columns(execute, &schema, &options.policies, date_columns,
+                          |c| format!("{0} - {0} % {1}", c, us))?;
+            scrub_columns(execute, &schema, &options.policies, ms_date_columns,
+                          |c| format!("{0} - {0} % {1}", c, ms))?;
+            // Rounding to whole seconds drops any fraction too.
+            scrub_columns(execute, &schema, &options.policies, SECONDS_DATE_COLUMNS,
+                          |c| format!("CAST({0} AS INTEGER) - CAST({0} AS INTEGER) % {1}", c, granularity.as_secs().max(1)))?;
+        }
+        if scrub_level >= 5 {
+            scrub_columns(execute, &schema, &options.policies, COUNT_COLUMNS, |c| format!("min({}, 0)", c))?;
+            scrub_columns(execute, &schema, &options.policies, date_columns, |c| format!("{} * 0", c))?;
+            scrub_columns(execute, &schema, &options.policies, ms_date_columns, |c| format!("{} * 0", c))?;
+            scrub_columns(execute, &schema, &options.policies, SECONDS_DATE_COLUMNS, |c| format!("{} * 0", c))?;
+        } else if scrub_level >= 3 {
+            scrub_columns(execute, &schema, &options.policies, COUNT_COLUMNS, |c| format!("min({}, 1)", c))?;
+        }
+        for (_, sql) in &triggers {
+            execute(sql)?;
+        }
+        if options.optimize {
+            // Updating every url leaves its indexes scattered and the
+            // statistics the query planner goes by out of date.
+            progress.phase("Rebuilding indexes and statistics".into());
+            execute("REINDEX")?;
+            execute("ANALYZE")?;
+        }
+        // The rows we deleted and the text we shortened leave free pages
+        // behind, which still hold the original data until they're reused,
+        // and keep the file as big as the input.
+        progress.phase("Vacuuming".into());
+        if !options.vacuum {
+            // Just drops the free pages from the end of the file, which is
+            // quick, but only works with incremental auto-vacuum. It returns
+            // rows, so can't go through `execute`.
+            if !options.dry_run {
+                anon_places.execute_batch("PRAGMA incremental_vacuum")?;
+            }
+        } else {
+            if fast_writes {
+                // VACUUM builds the new database as a temporary one, which
+                // would otherwise all be in memory.
+                execute("PRAGMA temp_store = FILE")?;
+            }
+            execute("VACUUM")?;
+        }
+        Ok(())
+    })();
+    let timed_out = watchdog.map(|w| w.stop()).unwrap_or(false);
+    drop(watching);
+    // Put back the safe settings (and WAL, if that's what it used) for
+    // whoever opens it next. A failed run's output isn't worth the bother.
+    if fast_writes && result.is_ok() {
+        anon_places.execute_batch(&format!("
+            PRAGMA synchronous = FULL;
+            PRAGMA journal_mode = {};
+        ", journal_mode))?;
+    }
+    drop(anon_places);
+    if options.dry_run {
+        result?;
+        return Ok(Outcome::DryRun(DryRun { tables: plans, sql: dry_run_sql.into_inner() }));
+    }
+    if options.cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst)) && result.is_err() {
+        bail!(Cancelled, "Cancelled with {} of {} tables anonymized, removed the partial output", tables_done, schema.len());
+    }
+    if timed_out && result.is_err() {
+        bail!(TimedOut, "Timed out after {:.1}s with {} of {} tables anonymized, removed the partial output",
+              started.elapsed().as_secs_f64(), tables_done, schema.len());
+    }
+    result?;
+    progress.phase("Checking the output".into());
+    check_integrity(anon_path, options.thorough_verify, "The anonymized output")?;
+    check_references(source_path, anon_path)?;
+
+    if options.thorough_verify {
+        info!("Verifying every cell against the source");
+        progress.phase("Verifying".into());
+        let mut cleared = CLEARED_COLUMNS.to_vec();
+        if options.strip_favicons {
+            cleared.push(("moz_icons", "data"));
+        }
+        // Wiped values are unchanged if they were empty already.
+        cleared.push(COOKIE_VALUES);
+        cleared.push(DOWNLOAD_HASHES);
+        if scrub_level >= 3 || options.count_noise.is_some() {
+            cleared.extend_from_slice(COUNT_COLUMNS);
+        }
+        if scrub_level >= 5 || options.shift_dates || options.round_dates.is_some() {
+            cleared.extend_from_slice(date_columns);
+            cleared.extend_from_slice(ms_date_columns);
+            cleared.extend_from_slice(SECONDS_DATE_COLUMNS);
+        }
+        // JSON without any strings is unchanged, and so are prefixes that are just
+        // a scheme.
+        let mut kept = vec![];
+        for info in &schema {
+            kept.extend(info.cols_of(ColumnClass::Json).map(|col| (info.name.as_str(), col)));
+            kept.extend(info.cols_of(ColumnClass::Prefix).map(|col| (info.name.as_str(), col)));
+            for col in &info.cols {
+                match options.policies.for_column(&info.name, col) {
+                    Some(Policy::Keep) => kept.push((info.name.as_str(), col.as_str())),
+                    Some(Policy::Null) | Some(Policy::Hash) => cleared.push((info.name.as_str(), col.as_str())),
+                    _ => {}
+                }
+            }
+        }
+        // Kept hosts are left as they are, along with their urls, and so are
+        // hosts that are just a public suffix when we keep those, and
+        // Firefox's own urls.
+        let anonymizer = anonymizer.borrow();
+        let kept_rev_host = |text: &str| {
+            let host = text.trim_end_matches('.').chars().rev().collect::<String>();
+            anonymizer.is_kept_host(&host) || anonymizer.is_public_suffix(&host)
+        };
+        let kept_value = |class, text: &str| match class {
+            ColumnClass::Url => anonymizer.is_kept_url(text) || anonymizer.is_kept_internal_url(text),
+            ColumnClass::Host => anonymizer.is_kept_host(text) || anonymizer.is_public_suffix(text),
+            ColumnClass::RevHost => kept_rev_host(text),
+            ColumnClass::Origin => anonymizer.is_kept_origin(text),
+            ColumnClass::Guid => is_bookmark_root(text),
+            ColumnClass::OriginKey => text.split(':').filter(|part| part.ends_with('.')).all(kept_rev_host),
+            // Like the root, `/`.
+            ColumnClass::Path => text.chars().all(|c| c == '/' || c == '\\'),
+            _ => false,
+        };
+        thorough_verify(source_path, anon_path, &schema, &exclusions, &cleared, &kept, &kept_value)?;
+    }
+    // Verifying doesn't watch for it, so check we weren't cancelled meanwhile.
+    if options.cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst)) {
+        bail!(Cancelled, "Cancelled after anonymizing, removed the partial output");
+    }
+
+    if let Some(min_reduction) = options.min_size_reduction {
+        let output_size = fs::metadata(anon_path)?.len();
+        let reduction = 100.0 * (1.0 - output_size as f64 / input_size as f64);
+        info!("Output is {:.1}% smaller than the input ({} -> {} bytes)",
+              reduction, input_size, output_size);
+        if reduction < min_reduction {
+            bail!(Verification, "Output is only {:.1}% smaller than the input ({} -> {} bytes), but {}% is required",
+                  reduction, input_size, output_size, min_reduction);
+        }
+    }
+
+    partial.expect("Only dry runs have no output").persist(output)
+        .map_err(|e| format_err!(Other, "Can't write {:?}: {}", output, e.error))?;
+
+    if let (Some(path), Some(passphrase)) = (&options.export_mapping, &options.mapping_passphrase) {
+        info!("Writing the encrypted mapping to {:?}", path);
+        write_mapping(path, &anonymizer.borrow().mapping(), passphrase)?;
+    }
+
+    Ok(Outcome::Anonymized { schema_version: version, tables: stats })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A places database with a page, a bookmark of it and the roots above that.
+    fn places_fixture(dir: &Path) -> PathBuf {
+        let path = dir.join("places.sqlite");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("
+            PRAGMA user_version = 52;
+            CREATE TABLE moz_places (
+                id INTEGER PRIMARY KEY, url LONGVARCHAR, title LONGVARCHAR, rev_host LONGVARCHAR,
+                visit_count INTEGER DEFAULT 0, hidden INTEGER DEFAULT 0 NOT NULL,
+                typed INTEGER DEFAULT 0 NOT NULL, frecency INTEGER DEFAULT -1 NOT NULL,
+                last_visit_date INTEGER, guid TEXT, foreign_count INTEGER DEFAULT 0 NOT NULL,
+                url_hash INTEGER DEFAULT 0 NOT NULL
+            );
+            CREATE TABLE moz_bookmarks (
+                id INTEGER PRIMARY KEY, type INTEGER, fk INTEGER DEFAULT NULL, parent INTEGER,
+                position INTEGER, title LONGVARCHAR, keyword_id INTEGER, folder_type TEXT,
+                dateAdded INTEGER, lastModified INTEGER, guid TEXT,
+                syncStatus INTEGER NOT NULL DEFAULT 0, syncChangeCounter INTEGER NOT NULL DEFAULT 1
+            );
+            INSERT INTO moz_places VALUES (1, 'https://example.com/secret?q=x', 'Secret page', 'moc.elpmaxe.',
+                                           7, 0, 2, 900, 1600000012345678, 'abcdefghijkl', 1, 0);
+            INSERT INTO moz_bookmarks VALUES
+                (1, 2, NULL, 0, 0, '', NULL, NULL, 1600000012345678, 1600000012345678, 'root________', 1, 1),
+                (2, 2, NULL, 1, 0, 'menu', NULL, NULL, 1600000012345678, 1600000012345678, 'menu________', 1, 1),
+                (3, 2, NULL, 1, 1, 'toolbar', NULL, NULL, 1600000012345678, 1600000012345678, 'toolbar_____', 1, 1),
+                (4, 1, 1, 3, 0, 'My secret', NULL, NULL, 1600000012345678, 1600000012345678, 'bkmkguid0001', 1, 1);
+        ").unwrap();
+        path
+    }
+
+    fn at_level(scrub_level: u8) -> Options {
+        apply_scrub_level(&Options { scrub_level, ..Options::default() })
+    }
+
+    #[test]
+    fn scrub_level_0_keeps_structure() {
+        let options = at_level(0);
+        assert!(options.keep_url_structure);
+        assert!(options.keep_public_suffix);
+        assert!(options.keep_query_keys);
+        assert!(options.keep_title_structure);
+        assert!(options.anonymize_downloads);
+        assert!(!options.regenerate_guids);
+        assert_eq!(options.round_dates, None);
+    }
+
+    #[test]
+    fn scrub_level_1_anonymizes_values() {
+        let options = at_level(1);
+        assert!(options.keep_url_structure);
+        assert!(!options.keep_public_suffix);
+        assert!(!options.keep_query_keys);
+        assert!(!options.keep_title_structure);
+        assert!(!options.anonymize_downloads);
+    }
+
+    #[test]
+    fn scrub_level_2_is_the_default() {
+        assert_eq!(Options::default().scrub_level, 2);
+        let options = at_level(2);
+        assert!(!options.keep_url_structure);
+        assert!(!options.keep_public_suffix);
+        assert!(!options.regenerate_guids);
+        assert!(!options.strip_query);
+        assert_eq!(options.round_dates, None);
+        assert_eq!(options.length_bucket, None);
+    }
+
+    #[test]
+    fn scrub_level_4_rounds_dates_to_the_day() {
+        assert_eq!(at_level(3).round_dates, None);
+        assert_eq!(at_level(4).round_dates, Some(Duration::from_secs(24 * 60 * 60)));
+        // Unless told otherwise.
+        let hour = Some(Duration::from_secs(60 * 60));
+        let options = apply_scrub_level(&Options { scrub_level: 4, round_dates: hour, ..Options::default() });
+        assert_eq!(options.round_dates, hour);
+    }
+
+    #[test]
+    fn scrub_level_5_scrubs_everything() {
+        let options = at_level(5);
+        assert!(!options.keep_url_structure);
+        assert!(options.regenerate_guids);
+        assert!(options.strip_query);
+        assert!(options.strip_favicons);
+        assert_eq!(options.length_bucket, Some(16));
+        assert!(options.round_dates.is_some());
+        let options = apply_scrub_level(&Options { scrub_level: 5, length_bucket: Some(8), ..Options::default() });
+        assert_eq!(options.length_bucket, Some(8));
+    }
+
+    #[test]
+    fn scrub_level_5_refuses_keys() {
+        let key = Some(vec![1; 32]);
+        assert!(check_options(&Options { scrub_level: 4, key: key.clone(), ..Options::default() }).is_ok());
+        match check_options(&Options { scrub_level: 5, key, ..Options::default() }) {
+            Err(Error::BadOptions(_)) => {}
+            other => panic!("Expected BadOptions, got {:?}", other),
+        }
+        match check_options(&Options { scrub_level: 6, ..Options::default() }) {
+            Err(Error::BadOptions(_)) => {}
+            other => panic!("Expected BadOptions, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bookmark_roots_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = places_fixture(dir.path());
+        let roots = [
+            (1, 0, "".to_owned(), "root________".to_owned()),
+            (2, 1, "menu".to_owned(), "menu________".to_owned()),
+            (3, 1, "toolbar".to_owned(), "toolbar_____".to_owned()),
+        ];
+        for (i, options) in [
+            Options::default(),
+            Options { regenerate_guids: true, ..Options::default() },
+            Options { mode: Mode::Stream, ..Options::default() },
+            Options { scrub_level: 5, ..Options::default() },
+        ].iter().enumerate() {
+            let output = dir.path().join(format!("roots{}.sqlite", i));
+            anonymize_places(&input, &output, &Options { progress: false, ..options.clone() }).unwrap();
+            let conn = Connection::open(&output).unwrap();
+            let mut stmt = conn.prepare("SELECT id, parent, title, guid FROM moz_bookmarks ORDER BY id").unwrap();
+            let rows = stmt.query_map(&[], |row| {
+                (row.get::<_, i64>(0), row.get::<_, i64>(1), row.get::<_, String>(2), row.get::<_, String>(3))
+            }).unwrap().collect::<::rusqlite::Result<Vec<_>>>().unwrap();
+            assert_eq!(rows[..3], roots, "with {:?}", options);
+            // While the bookmark in them is anonymized, but stays where it was.
+            assert_eq!(rows[3].1, 3);
+            assert_ne!(rows[3].2, "My secret");
+            assert_ne!(rows[3].3, "bkmkguid0001");
+        }
+    }
+
+    #[test]
+    fn scrub_levels_scrub_counts_and_dates() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = places_fixture(dir.path());
+        for &(level, counts, date) in &[
+            (2, (7, 900), 1_600_000_012_345_678),
+            (3, (1, 1), 1_600_000_012_345_678),
+            (4, (1, 1), 1_599_955_200_000_000),
+            (5, (0, 0), 0),
+        ] {
+            let output = dir.path().join(format!("level{}.sqlite", level));
+            let options = Options { scrub_level: level, progress: false, ..Options::default() };
+            anonymize_places(&input, &output, &options).unwrap();
+            let conn = Connection::open(&output).unwrap();
+            let row = conn.query_row("SELECT visit_count, frecency, last_visit_date FROM moz_places", &[], |row| {
+                ((row.get::<_, i64>(0), row.get::<_, i64>(1)), row.get::<_, i64>(2))
+            }).unwrap();
+            assert_eq!(row, (counts, date), "at scrub level {}", level);
+        }
+    }
+
+    #[test]
+    fn hash_policy_is_keyed() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = places_fixture(dir.path());
+        let policies = Policies::parse("[moz_places]\ntitle = \"hash\"\nvisit_count = \"hash\"").unwrap();
+        let hashed = |name: &str, key: u8| {
+            let output = dir.path().join(name);
+            let options = Options { policies: policies.clone(), key: Some(vec![key; 32]), progress: false, ..Options::default() };
+            anonymize_places(&input, &output, &options).unwrap();
+            let conn = Connection::open(&output).unwrap();
+            conn.query_row("SELECT title, visit_count FROM moz_places", &[], |row| {
+                (row.get::<_, String>(0), row.get::<_, String>(1))
+            }).unwrap()
+        };
+        let (title, count) = hashed("hash1.sqlite", 1);
+        assert_eq!(title.len(), 32);
+        assert!(title.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(count, "7");
+        assert_eq!(hashed("hash2.sqlite", 1), (title.clone(), count));
+        assert_ne!(hashed("hash3.sqlite", 2).0, title);
+    }
+}
@@ -1,155 +1,521 @@
-extern crate rusqlite;
-extern crate dirs;
-
+extern crate anonymize_places;
 #[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate log;
-extern crate rand;
 extern crate clap;
 extern crate env_logger;
+#[macro_use]
+extern crate serde_json;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(feature = "upload")]
+extern crate ureq;
+#[cfg(feature = "upload")]
+extern crate base64;
 
-use rand::prelude::*;
-use std::{process, fs, path::{Path, PathBuf}};
-use std::collections::HashMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use rusqlite::{Connection, OpenFlags};
+use anonymize_places::{Profile, ProfileStats, Options, Mode, Engine, Outcome, DryRun, TableStats, Policies, Session, Error};
+use anonymize_places::{get_profiles, get_profiles_in_dir, sort_profiles, find_profile, format_prtime, mapping_entries};
+use anonymize_places::find_leaks;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
-#[derive(Clone, Debug)]
-struct Profile {
-    name: String,
-    places_db: PathBuf,
-    db_size: u64,
+// Prints a side-by-side summary of the history in each profile. Profiles we
+// can't read are still listed, with the error in place of their stats.
+fn compare_profiles(profiles: &[Profile]) {
+    println!("{:<40} {:>10} {:>10} {:>8}  VISITS", "PROFILE", "SIZE", "PLACES", "HOSTS");
+    for p in profiles {
+        let name = p.qualified_name();
+        match ProfileStats::for_db(&p.places_db) {
+            Ok(stats) => {
+                let range = match (stats.first_visit, stats.last_visit) {
+                    (Some(first), Some(last)) =>
+                        format!("{} to {}", format_prtime(first), format_prtime(last)),
+                    _ => "never".into(),
+                };
+                println!("{:<40} {:>10} {:>10} {:>8}  {}",
+                         name, p.friendly_db_size(), stats.places, stats.hosts, range);
+            }
+            Err(e) => {
+                println!("{:<40} {:>10} <error: {}>", name, p.friendly_db_size(), e);
+            }
+        }
+    }
 }
 
-impl Profile {
-    fn friendly_db_size(&self) -> String {
-        let sizes = [
-            (1024 * 1024 * 1024, "Gb"),
-            (1024 * 1024, "Mb"),
-            (1024, "Kb"),
-        ];
-        for (lim, suffix) in &sizes {
-            if self.db_size >= *lim {
-
-                return format!("~{} {}", ((self.db_size as f64 / *lim as f64) * 10.0).round() / 10.0, suffix);
-            }
+// Makes the first Ctrl-C (or SIGTERM) cancel anonymizing, so that the partial
+// output and temporary files are removed on the way out rather than left
+// behind with half the history still in them. A second one kills us outright.
+#[cfg(unix)]
+fn cancel_on_ctrl_c() -> Option<Arc<AtomicBool>> {
+    static CANCEL: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    extern "C" fn on_signal(_: libc::c_int) {
+        if let Some(cancel) = CANCEL.get() {
+            cancel.store(true, Ordering::SeqCst);
+        }
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+            libc::signal(libc::SIGTERM, libc::SIG_DFL);
         }
-        format!("{} bytes", self.db_size)
     }
+    let cancel = CANCEL.get_or_init(|| Arc::new(AtomicBool::new(false))).clone();
+    unsafe {
+        libc::signal(libc::SIGINT, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    Some(cancel)
 }
 
-// Only used if we 
-fn get_profiles() -> Result<Vec<Profile>> {
-    let mut path = match dirs::home_dir() {
-        Some(dir) => dir,
-        None => bail!("No home directory found!")
-    };
-    if cfg!(windows) {
-        path.extend(&["AppData", "Roaming", "Mozilla", "Firefox", "Profiles"]);
+#[cfg(not(unix))]
+fn cancel_on_ctrl_c() -> Option<Arc<AtomicBool>> {
+    None
+}
+
+// Returns the first of `foo.1.sqlite`, `foo.2.sqlite`, ... that doesn't exist yet.
+fn next_available_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+    let ext = path.extension().map(|e| e.to_string_lossy().into_owned());
+    (1..).map(|i| {
+        let name = match ext {
+            Some(ref ext) => format!("{}.{}.{}", stem, i, ext),
+            None => format!("{}.{}", stem, i),
+        };
+        path.with_file_name(name)
+    }).find(|p| !p.exists()).unwrap()
+}
+
+// Where to write the output that was asked to go to `path`, going by -f and
+// --auto-name if something's already there. A dry run doesn't write anything,
+// so doesn't mind.
+fn check_output_path(path: PathBuf, matches: &clap::ArgMatches) -> Result<PathBuf> {
+    if !path.exists() || matches.is_present("force") || matches.is_present("dry-run") {
+        return Ok(path);
+    }
+    if matches.is_present("auto-name") {
+        let path = next_available_path(&path);
+        println!("Writing to {:?}", path);
+        Ok(path)
     } else {
-        let out = String::from_utf8(
-            process::Command::new("uname").args(&["-s"]).output()?.stdout)?;
-        println!("Uname says: {:?}", out);
-        if out.trim() == "Darwin" {
-            // ~/Library/Application Support/Firefox/Profiles
-            path.extend(&["Library", "Application Support", "Firefox", "Profiles"]);
-        } else {
-            // I'm not actually sure if this is true for all non-macos unix likes.
-            path.extend(&[".mozilla", "firefox"]);
-        }
-    }
-    debug!("Using profile path: {:?}", path);
-    let res = fs::read_dir(path)?
-    .map(|entry_result| {
-        let entry = entry_result?;
-        trace!("Considering path {:?}", entry.path());
-        if !entry.path().is_dir() {
-            trace!("  Not dir: {:?}", entry.path());
-            return Ok(None);
-        }
-        let mut path = entry.path().to_owned();
-        let profile_name = path.file_name().unwrap_or_default().to_str().ok_or_else(|| {
-            warn!("  Path has invalid UTF8: {:?}", path);
-            format_err!("Path has invalid UTF8: {:?}", path)
-        })?.into();
-        path.push("places.sqlite");
-        if !path.exists() {
-            return Ok(None);
-        }
-        let metadata = fs::metadata(&path)?;
-        let db_size = metadata.len();
-        Ok(Some(Profile {
-            name: profile_name,
-            places_db: path,
-            db_size,
-        }))
-    }).filter_map(|result: Result<Option<Profile>>| {
-        match result {
-            Ok(val) => val,
-            Err(e) => {
-                debug!("Got error finding profile directory, skipping: {}", e);
-                None
-            }
-        }
-    }).collect::<Vec<_>>();
-    Ok(res)
+        eprintln!("Error: {} already exists but `-f` argument was not provided", path.to_str().unwrap());
+        Err(Error::OutputExists(path).into())
+    }
 }
-#[derive(Default, Clone, Debug)]
-struct StringAnonymizer {
-    table: HashMap<String, String>,
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() || s.len() % 2 == 1 || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
 }
 
-fn rand_string_of_len(len: usize) -> String {
-    let mut rng = thread_rng();
-    rng.sample_iter(&rand::distributions::Alphanumeric).take(len).collect()
+const SCRUB_LEVEL_HELP: &str = "\
+Sets the privacy options at once, from 0 (keep as much structure as possible) \
+to 5 (scrub as much as possible). Each level scrubs everything the ones below it do, \
+and options given as well still apply:
+    0: anonymize only the values in urls, titles and download paths, keeping \
+schemes, public suffixes, query keys, the words of titles and the segments of \
+paths (--keep-url-structure --keep-public-suffix --keep-query-keys \
+--keep-title-structure --anonymize-downloads)
+    1: also anonymize public suffixes, query keys, and titles and download paths \
+whole, keeping only the scheme and shape of urls (--keep-url-structure)
+    2: also anonymize urls whole (the default)
+    3: also reduce visit counts, typed counts, use counts, frecencies and page \
+interaction times to 0 or 1
+    4: also truncate every date to the day (--round-dates day, unless given)
+    5: also replace GUIDs, strip queries and icons, zero out every count and date, \
+and pad text to multiples of 16 characters (--regenerate-guids --strip-query \
+--strip-favicons --bucket-lengths 16, unless given), and refuse options that \
+record anything derived from the originals (--fingerprint-salt, --key)
+";
+
+// Streams the file at `path` to `url` as the body of a POST request.
+#[cfg(feature = "upload")]
+fn upload(path: &Path, url: &str, headers: &[&str], auth: Option<&str>) -> Result<()> {
+    use base64::Engine;
+    let size = fs::metadata(path)?.len();
+    let mut request = ureq::post(url)
+        .set("Content-Type", "application/octet-stream")
+        .set("Content-Length", &size.to_string());
+    for header in headers {
+        let (name, value) = match header.find(':') {
+            Some(i) => (header[..i].trim(), header[i + 1..].trim()),
+            None => bail!("Bad --post-header {:?}, expected `Name: value`", header),
+        };
+        request = request.set(name, value);
+    }
+    if let Some(auth) = auth {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(auth);
+        request = request.set("Authorization", &format!("Basic {}", encoded));
+    }
+    println!("Uploading {:?} to {}", path, url);
+    let response = request.send(fs::File::open(path)?)
+        .map_err(|e| format_err!("Upload to {} failed: {}", url, e))?;
+    info!("Upload finished with status {}", response.status());
+    Ok(())
 }
 
-impl StringAnonymizer {
+#[cfg(not(feature = "upload"))]
+fn upload(_path: &Path, _url: &str, _headers: &[&str], _auth: Option<&str>) -> Result<()> {
+    bail!("--post-to requires anonymize-places to be built with the `upload` feature");
+}
 
-    fn anonymize(&mut self, s: &str) -> String {
-        if s.len() == 0 {
-            return "".into();
+// The passphrase for the mapping file, from the environment if it's set there,
+// or else from a line of stdin.
+fn mapping_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var("ANONYMIZE_PLACES_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    eprint!("Passphrase for the mapping: ");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let passphrase = line.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        bail!("The mapping needs a passphrase");
+    }
+    Ok(passphrase.into())
+}
+
+// The hosts listed in a --keep-hosts file, one per line, with # starting a
+// comment.
+fn read_keep_hosts(path: Option<&str>) -> Result<Vec<String>> {
+    Ok(match path {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| format_err!("Can't read --keep-hosts file {:?}: {}", path, e))?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .filter(|host| !host.is_empty())
+            .map(|host| host.into())
+            .collect(),
+        None => vec![],
+    })
+}
+
+// The profiles in --profile-dir, if it's given, or else the ones we find in
+// the usual places.
+fn discover_profiles(matches: &clap::ArgMatches) -> Result<Vec<Profile>> {
+    Ok(match matches.value_of("profile-dir") {
+        Some(dir) => get_profiles_in_dir(Path::new(dir))?,
+        None => get_profiles()?,
+    })
+}
+
+// Prints the profiles we found, largest first, as JSON with `--json`.
+fn list_profiles(matches: &clap::ArgMatches) -> Result<()> {
+    let mut profiles = discover_profiles(matches)?;
+    sort_profiles(&mut profiles);
+    if matches.is_present("json") {
+        let profiles = profiles.iter().map(|p| json!({
+            "name": p.qualified_name(),
+            "install": p.install,
+            "profile": p.name,
+            "path": p.places_db,
+            "schema_version": p.schema_version().ok(),
+            "size": p.db_size,
+            "default": p.is_default,
+            "chromium": p.is_chromium,
+        })).collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&profiles)?);
+        return Ok(());
+    }
+    for p in &profiles {
+        let default = if p.is_default { ", default" } else { "" };
+        println!("{} ({}{})", p.qualified_name(), p.friendly_db_size(), default);
+    }
+    Ok(())
+}
+
+// Lets the user pick one of `profiles` from a numbered menu, with `default`
+// picked if they just press enter.
+fn pick_profile(mut profiles: Vec<Profile>, default: usize) -> Result<Profile> {
+    for (i, p) in profiles.iter().enumerate() {
+        let marker = if i == default { " (default)" } else { "" };
+        eprintln!("{:>3}. {} ({}){}", i + 1, p.qualified_name(), p.friendly_db_size(), marker);
+    }
+    loop {
+        eprint!("Profile to anonymize [{}]: ", default + 1);
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            bail!("No profile picked");
         }
-        if let Some(a) = self.table.get(s) {
-            return a.clone();
+        let choice = line.trim();
+        if choice.is_empty() {
+            return Ok(profiles.swap_remove(default));
         }
-        for i in 0..10 {
-            let replacement = rand_string_of_len(s.len());
-            // keep trying but force it at the last time
-            if self.table.get(&replacement).is_some() && i != 9 {
-                continue;
+        match choice.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= profiles.len() => return Ok(profiles.swap_remove(n - 1)),
+            _ => eprintln!("Enter a number from 1 to {}", profiles.len()),
+        }
+    }
+}
+
+// If the browser has `profile` open, takes a snapshot of what's on disk
+// anyway, waits for it to close, or gives up, as --if-running says, or as the
+// user says if we can ask them.
+fn check_not_running(profile: &Profile, matches: &clap::ArgMatches, cancel: Option<&AtomicBool>) -> Result<()> {
+    if !profile.in_use() {
+        return Ok(());
+    }
+    let action = match matches.value_of("if-running") {
+        Some(action) => action.to_owned(),
+        None if !matches.is_present("non-interactive") && std::io::stdin().is_terminal() => loop {
+            eprint!("Firefox seems to be using {:?}. [s]napshot it anyway, [w]ait for it to close, or [a]bort? [a]: ",
+                    profile.places_db);
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line)? == 0 {
+                break "abort".to_owned();
+            }
+            match line.trim() {
+                "s" | "snapshot" => break "snapshot".to_owned(),
+                "w" | "wait" => break "wait".to_owned(),
+                "" | "a" | "abort" => break "abort".to_owned(),
+                _ => eprintln!("Enter s, w or a"),
             }
+        },
+        None => "snapshot".to_owned(),
+    };
+    match action.as_str() {
+        "wait" => {
+            eprintln!("Waiting for Firefox to close {:?} (Ctrl-C to give up)", profile.places_db);
+            while profile.in_use() {
+                if cancel.is_some_and(|c| c.load(Ordering::SeqCst)) {
+                    bail!("Cancelled while waiting for Firefox to close {:?}", profile.places_db);
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+        "abort" => bail!("Firefox is using {:?}; close it and try again, or pass --if-running snapshot",
+                         profile.places_db),
+        _ => warn!("Firefox is using {:?}, so the copy may be missing its latest changes", profile.places_db),
+    }
+    Ok(())
+}
 
-            self.table.insert(s.into(), replacement.clone());
-            return replacement;
+// Prints what was done to each table, as --stats asks.
+fn print_stats(tables: &[TableStats]) {
+    if tables.is_empty() {
+        return;
+    }
+    println!("{:<40} {:>10} {:>10} {:>10} {:>10}", "TABLE", "ROWS", "UPDATED", "DELETED", "STRINGS");
+    for t in tables {
+        println!("{:<40} {:>10} {:>10} {:>10} {:>10}", t.name, t.rows, t.updated_rows, t.deleted_rows, t.distinct_strings);
+    }
+}
+
+// The options for --json-summary, apart from secrets (like the key), which
+// are only said to be there.
+fn options_summary(options: &Options) -> serde_json::Value {
+    let mut summary = serde_json::Map::new();
+    let mut add = |name: &str, value: serde_json::Value| { summary.insert(name.into(), value); };
+    add("mode", json!(format!("{:?}", options.mode).to_lowercase()));
+    add("engine", json!(format!("{:?}", options.engine).to_lowercase()));
+    add("batch_size", json!(options.batch_size));
+    add("fast_writes", json!(options.fast_writes));
+    add("threads", json!(options.threads));
+    add("memory_budget", json!(options.memory_budget));
+    add("scrub_level", json!(options.scrub_level));
+    add("anonymize_downloads", json!(options.anonymize_downloads));
+    add("fingerprint_salt", json!(options.fingerprint_salt.is_some()));
+    add("seed", json!(options.seed.is_some()));
+    add("key", json!(options.key.is_some()));
+    add("strict", json!(options.strict));
+    add("allowed_tables", json!(options.allowed_tables));
+    add("tables", json!(options.tables));
+    add("exclude_tables", json!(options.exclude_tables));
+    add("generic", json!(options.generic));
+    add("allow_unknown_schema_version", json!(options.allow_unknown_schema_version));
+    add("only_schema_version", json!(options.only_schema_version));
+    add("keep_url_structure", json!(options.keep_url_structure));
+    add("keep_query_keys", json!(options.keep_query_keys));
+    add("strip_query", json!(options.strip_query));
+    add("keep_title_structure", json!(options.keep_title_structure));
+    add("keep_hosts", json!(options.keep_hosts));
+    add("keep_public_suffix", json!(options.keep_public_suffix));
+    add("regenerate_guids", json!(options.regenerate_guids));
+    add("length_bucket", json!(options.length_bucket));
+    add("shift_dates", json!(options.shift_dates));
+    add("round_dates_secs", json!(options.round_dates.map(|d| d.as_secs())));
+    add("count_noise", json!(options.count_noise));
+    add("strip_favicons", json!(options.strip_favicons));
+    add("hash_cookie_values", json!(options.hash_cookie_values));
+    add("secure_rng", json!(options.secure_rng));
+    add("export_mapping", json!(options.export_mapping));
+    add("import_mapping", json!(options.import_mapping));
+    add("salvage", json!(options.salvage));
+    add("thorough_verify", json!(options.thorough_verify));
+    add("optimize", json!(options.optimize));
+    add("vacuum", json!(options.vacuum));
+    add("min_size_reduction", json!(options.min_size_reduction));
+    add("max_runtime_secs", json!(options.max_runtime.map(|d| d.as_secs())));
+    add("dry_run", json!(options.dry_run));
+    serde_json::Value::Object(summary)
+}
+
+// What --json-summary says about anonymizing `input` to `output`, which took
+// `duration` and ended with `outcome` (or failed with its error).
+fn database_summary(input: &Path, output: &Path, outcome: &anonymize_places::Result<Outcome>, duration: Duration) -> serde_json::Value {
+    let (result, schema_version, tables, output_size) = match *outcome {
+        Ok(Outcome::Anonymized { schema_version, ref tables }) => {
+            let tables = tables.iter().map(|t| json!({
+                "name": t.name,
+                "rows": t.rows,
+                "updated_rows": t.updated_rows,
+                "deleted_rows": t.deleted_rows,
+                "distinct_strings": t.distinct_strings,
+            })).collect::<Vec<_>>();
+            ("anonymized", Some(schema_version), tables, fs::metadata(output).ok().map(|m| m.len()))
         }
-        unreachable!("Bug in anonymize retry loop");
+        Ok(Outcome::Skipped { schema_version }) => ("skipped", Some(schema_version), vec![], None),
+        Ok(Outcome::DryRun(_)) => ("dry-run", None, vec![], None),
+        Err(_) => ("failed", None, vec![], None),
+    };
+    json!({
+        "input": input,
+        "output": output,
+        "result": result,
+        "error": outcome.as_ref().err().map(|e| e.to_string()),
+        "schema_version": schema_version,
+        "tables": tables,
+        "duration_secs": duration.as_secs_f64(),
+        "input_size": fs::metadata(input).ok().map(|m| m.len()),
+        "output_size": output_size,
+    })
+}
+
+// Writes the --json-summary of a run, if one was asked for, to its path (or
+// stdout, for `-`).
+fn write_summary(matches: &clap::ArgMatches, options: &serde_json::Value, databases: &[serde_json::Value],
+                 started: Instant) -> Result<()> {
+    let path = match matches.value_of("json-summary") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let summary = serde_json::to_string_pretty(&json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "options": options,
+        "databases": databases,
+        "duration_secs": started.elapsed().as_secs_f64(),
+    }))?;
+    if path == "-" {
+        println!("{}", summary);
+    } else {
+        fs::write(path, summary + "\n").map_err(|e| format_err!("Can't write summary {:?}: {}", path, e))?;
     }
+    Ok(())
+}
 
+// Prints each table and the SQL that a dry run found would be used to
+// anonymize a database.
+fn print_dry_run(plan: &DryRun) {
+    for table in &plan.tables {
+        let deleted = match table.deleted_rows {
+            0 => String::new(),
+            n => format!(", {} deleted by the policies", n),
+        };
+        let changes = if table.left_as_is {
+            "left as is".into()
+        } else if table.columns.is_empty() {
+            "nothing to anonymize".into()
+        } else {
+            format!("anonymizing {}", table.columns.join(", "))
+        };
+        println!("  {}: {} rows{}, {}", table.name, table.rows, deleted, changes);
+    }
+    if !plan.sql.is_empty() {
+        println!("SQL that would run:");
+        for sql in &plan.sql {
+            println!("{};", sql);
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-struct TableInfo {
-    name: String,
-    cols: Vec<String>
+// Anonymizes each of `profiles` into the OUTPUT directory as
+// `<name>_anonymized.sqlite`, with the install in the name too if profiles in
+// different installs share a name (ignoring case, for case insensitive file
+// systems). Profiles that fail don't stop the others.
+fn anonymize_all_profiles(profiles: &[Profile], matches: &clap::ArgMatches, options: Options,
+                          summary: &mut Vec<serde_json::Value>) -> Result<()> {
+    let dir = PathBuf::from(matches.value_of("OUTPUT").unwrap_or("."));
+    if !options.dry_run {
+        fs::create_dir_all(&dir).map_err(|e| format_err!("Can't create output directory {:?}: {}", dir, e))?;
+    }
+    let cancel = options.cancel.clone();
+    let session = Session::new(options)?;
+    let mut failed = 0;
+    for p in profiles {
+        let name = if profiles.iter().filter(|other| other.name.eq_ignore_ascii_case(&p.name)).count() > 1 {
+            format!("{}-{}", p.install, p.name)
+        } else {
+            p.name.clone()
+        };
+        let name = name.chars()
+            .map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+            .collect::<String>();
+        let output_path = match check_output_path(dir.join(format!("{}_anonymized.sqlite", name)), matches) {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("Failed to anonymize {:?}: {}", p.qualified_name(), e);
+                failed += 1;
+                continue;
+            }
+        };
+        if let Err(e) = check_not_running(p, matches, cancel.as_deref()) {
+            eprintln!("Failed to anonymize {:?}: {}", p.qualified_name(), e);
+            failed += 1;
+            continue;
+        }
+        println!("Anonymizing {:?} to {:?}", p.qualified_name(), output_path);
+        let started = Instant::now();
+        let outcome = session.anonymize(&p.places_db, &output_path);
+        summary.push(database_summary(&p.places_db, &output_path, &outcome, started.elapsed()));
+        match outcome {
+            Ok(Outcome::Anonymized { tables, .. }) if matches.is_present("stats") => print_stats(&tables),
+            Ok(Outcome::Anonymized { .. }) => {}
+            Ok(Outcome::Skipped { schema_version }) => {
+                println!("Skipping {:?}: schema version {} doesn't match --only-schema-version",
+                         p.qualified_name(), schema_version);
+            }
+            Ok(Outcome::DryRun(plan)) => print_dry_run(&plan),
+            Err(e) => {
+                eprintln!("Failed to anonymize {:?}: {}", p.qualified_name(), e);
+                failed += 1;
+            }
+        }
+        if cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst)) {
+            bail!("Cancelled, so the remaining profiles weren't anonymized");
+        }
+    }
+    if failed > 0 {
+        bail!("Failed to anonymize {} of {} profiles", failed, profiles.len());
+    }
+    println!("Done!");
+    Ok(())
 }
 
-impl TableInfo {
-    fn for_table(name: String, conn: &Connection) -> Result<TableInfo> {
-        let stmt = conn.prepare(&format!("SELECT * FROM {}", name))?;
-        let cols = stmt.column_names().into_iter().map(|x| x.to_owned()).collect();
-        Ok(TableInfo { name, cols })
+// The session store files in a profile: the current one, if Firefox was shut
+// down cleanly, and the backups it keeps while running and across upgrades.
+fn session_store_files(profile_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let current = profile_dir.join("sessionstore.jsonlz4");
+    if current.is_file() {
+        files.push(current);
     }
-    fn make_update(&self, updater_fn: &str) -> String {
-        let sets = self.cols.iter()
-            .map(|col| format!("{} = {}({})", col, updater_fn, col))
-            .collect::<Vec<_>>()
-            .join(",\n    ");
-        format!("UPDATE {}\nSET {}", self.name, sets)
+    let backups = profile_dir.join("sessionstore-backups");
+    if backups.is_dir() {
+        let mut entries = fs::read_dir(&backups)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        entries.retain(|path| path.is_file());
+        entries.sort();
+        files.extend(entries);
     }
+    Ok(files)
 }
 
 fn main() -> Result<()> {
@@ -159,7 +525,11 @@ fn main() -> Result<()> {
             .help("Path where we should output the anonymized db (defaults to places_anonymized.sqlite)"))
         .arg(clap::Arg::with_name("PLACES")
             .index(2)
-            .help("Path to places.sqlite. If not provided, we'll use the largest places.sqlite in your firefox profiles"))
+            .multiple(true)
+            .help("Path to places.sqlite. If not provided, we'll use your default Firefox profile's places.sqlite \
+                   (or the largest, if profiles.ini doesn't say). \
+                   Any other databases given after it (like favicons.sqlite) are anonymized with the same \
+                   replacements, and OUTPUT is then a directory to write them all to"))
         .arg(clap::Arg::with_name("v")
             .short("v")
             .multiple(true)
@@ -168,6 +538,357 @@ fn main() -> Result<()> {
             .short("f")
             .long("force")
             .help("Overwrite OUTPUT if it already exists"))
+        .arg(clap::Arg::with_name("auto-name")
+            .long("auto-name")
+            .conflicts_with("force")
+            .help("If OUTPUT already exists, write to the next free name (e.g. places_anonymized.1.sqlite) instead"))
+        .arg(clap::Arg::with_name("profile")
+            .long("profile")
+            .takes_value(true)
+            .value_name("NAME")
+            .conflicts_with("PLACES")
+            .help("Use the profile with this name (or directory name) instead of the default one. \
+                   Use INSTALL/NAME (as printed by list-profiles) if the name is ambiguous"))
+        .arg(clap::Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Print each table's row count and which of its columns would be rewritten, and the SQL that \
+                   would run, without writing OUTPUT"))
+        .arg(clap::Arg::with_name("engine")
+            .long("engine")
+            .takes_value(true)
+            .possible_values(&["sql", "rows"])
+            .default_value("sql")
+            .help("How to update each table: with one UPDATE statement (sql), or a chunk of rows at a \
+                   time, showing how many rows a second it's doing (rows), which is slower but shows \
+                   how far it's got on big tables"))
+        .arg(clap::Arg::with_name("batch-size")
+            .long("batch-size")
+            .takes_value(true)
+            .value_name("N")
+            .help("With --engine rows, commit every N rows rather than once per table, which keeps the \
+                   journal (and memory use) down on very big databases"))
+        .arg(clap::Arg::with_name("threads")
+            .long("threads")
+            .takes_value(true)
+            .value_name("N")
+            .help("Work out the replacements for each table's text and urls on N threads before updating \
+                   it, which is faster on big databases with many cores. Has no effect with --seed"))
+        .arg(clap::Arg::with_name("max-memory")
+            .long("max-memory")
+            .takes_value(true)
+            .value_name("MB")
+            .help("Keep at most about MB megabytes of replacements in memory, and the rest in a temporary \
+                   database, which is slower, but works for databases with more distinct urls and titles \
+                   than fit in memory"))
+        .arg(clap::Arg::with_name("safe-writes")
+            .long("safe-writes")
+            .help("Journal and sync the output to disk as SQLite usually does while anonymizing it, \
+                   which is much slower on big databases, rather than only once it's done"))
+        .arg(clap::Arg::with_name("no-progress")
+            .long("no-progress")
+            .help("Don't show what we're doing on a status line (which we only do when stderr is a terminal)"))
+        .arg(clap::Arg::with_name("stats")
+            .long("stats")
+            .help("Print how many rows each table had, how many were updated and deleted, and how many \
+                   distinct strings were anonymized in it"))
+        .arg(clap::Arg::with_name("json-summary")
+            .long("json-summary")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write a JSON summary of the run to FILE (or stdout, for -): the options, and each database's \
+                   input, output, schema version, table stats (as --stats prints), sizes and how long it took"))
+        .arg(clap::Arg::with_name("profile-dir")
+            .long("profile-dir")
+            .takes_value(true)
+            .value_name("DIR")
+            .env("ANONYMIZE_PLACES_PROFILE_DIR")
+            .global(true)
+            .help("Look for profiles in DIR instead of the usual places, e.g. for a portable install or a backup. \
+                   DIR can have a profiles.ini, hold profile directories, or be a profile itself"))
+        .arg(clap::Arg::with_name("all-profiles")
+            .long("all-profiles")
+            .conflicts_with_all(&["PLACES", "profile", "session-store", "post-to"])
+            .help("Anonymize every profile we find, each to <name>_anonymized.sqlite in OUTPUT, \
+                   which is a directory (the current one by default)"))
+        .arg(clap::Arg::with_name("non-interactive")
+            .long("non-interactive")
+            .help("Don't ask which profile to use when there's more than one, just use the default one. \
+                   We never ask when stdin isn't a terminal"))
+        .arg(clap::Arg::with_name("if-running")
+            .long("if-running")
+            .takes_value(true)
+            .value_name("ACTION")
+            .possible_values(&["snapshot", "wait", "abort"])
+            .help("What to do if Firefox has the profile open: take a snapshot anyway (which may miss \
+                   changes it hasn't written yet), wait for it to close, or abort. We ask if stdin is a \
+                   terminal, and otherwise take a snapshot"))
+        .arg(clap::Arg::with_name("session-store")
+            .long("session-store")
+            .help("Also anonymize the profile's session store (sessionstore.jsonlz4 and the files in \
+                   sessionstore-backups), with the same replacements. OUTPUT is then a directory"))
+        .arg(clap::Arg::with_name("strip-favicons")
+            .long("strip-favicons")
+            .help("Remove the icon images from favicons.sqlite, which can identify the sites \
+                   they're from even with their urls anonymized"))
+        .arg(clap::Arg::with_name("hash-cookie-values")
+            .long("hash-cookie-values")
+            .help("Replace the values of cookies.sqlite's cookies with keyed hashes, so equal \
+                   values stay equal, rather than wiping them"))
+        .arg(clap::Arg::with_name("export-mapping")
+            .long("export-mapping")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write what each string was replaced with to FILE, encrypted with a passphrase \
+                   (from $ANONYMIZE_PLACES_PASSPHRASE, or else asked for), so a replacement can \
+                   be looked up later with --show-mapping"))
+        .arg(clap::Arg::with_name("import-mapping")
+            .long("import-mapping")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Reuse the replacements in a file written by --export-mapping (with the same \
+                   passphrase), so strings anonymized in that run get the same replacements, \
+                   like when anonymizing a newer copy of the same profile"))
+        .arg(clap::Arg::with_name("show-mapping")
+            .long("show-mapping")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["export-mapping", "import-mapping"])
+            .help("Decrypt a file written by --export-mapping, print each replacement in it (as \
+                   TABLE, REPLACEMENT and ORIGINAL, separated by tabs) and exit"))
+        .arg(clap::Arg::with_name("compare-profiles")
+            .long("compare-profiles")
+            .help("Show history stats for each profile we found and exit"))
+        .arg(clap::Arg::with_name("no-vacuum")
+            .long("no-vacuum")
+            .conflicts_with("assert-size-reduction")
+            .help("Don't VACUUM the output at the end, which can take longer than anonymizing it. It only \
+                   shrinks if it uses incremental auto-vacuum (like Firefox's places.sqlite does). \
+                   WARNING: the output can then still hold data deleted from the input before it was \
+                   copied (like cleared history), in its free space, so don't share it"))
+        .arg(clap::Arg::with_name("optimize")
+            .long("optimize")
+            .help("Once anonymized, rebuild the output's indexes (REINDEX) and query planner statistics \
+                   (ANALYZE), so it performs like the original would, for benchmarking"))
+        .arg(clap::Arg::with_name("assert-size-reduction")
+            .long("assert-size-reduction")
+            .takes_value(true)
+            .value_name("PERCENT")
+            .help("Fail unless the output is at least PERCENT smaller than the input"))
+        .arg(clap::Arg::with_name("only-schema-version")
+            .long("only-schema-version")
+            .takes_value(true)
+            .value_name("N")
+            .help("Skip the database unless its places schema version (PRAGMA user_version) is N"))
+        .arg(clap::Arg::with_name("post-to")
+            .long("post-to")
+            .takes_value(true)
+            .value_name("URL")
+            .help("Upload the anonymized db to URL with an HTTP POST once everything else succeeded \
+                   (requires the `upload` feature)"))
+        .arg(clap::Arg::with_name("post-header")
+            .long("post-header")
+            .takes_value(true)
+            .value_name("NAME: VALUE")
+            .multiple(true)
+            .number_of_values(1)
+            .requires("post-to")
+            .help("Extra HTTP header to send with --post-to. May be repeated"))
+        .arg(clap::Arg::with_name("post-auth")
+            .long("post-auth")
+            .takes_value(true)
+            .value_name("USER:PASSWORD")
+            .requires("post-to")
+            .help("HTTP basic auth credentials for --post-to"))
+        .arg(clap::Arg::with_name("anonymize-downloads")
+            .long("anonymize-downloads")
+            .help("Keep the structure of download annotations, anonymizing the file paths in them \
+                   segment by segment"))
+        .arg(clap::Arg::with_name("fingerprint-salt")
+            .long("fingerprint-salt")
+            .takes_value(true)
+            .value_name("SALT")
+            .help("Record a salted hash of each original url in a url_fingerprints table, so the \
+                   same url can be matched across databases anonymized with the same SALT. \
+                   This makes the output pseudonymous rather than anonymous: anyone with SALT \
+                   can check whether a given url is present"))
+        .arg(clap::Arg::with_name("scrub-level")
+            .long("scrub-level")
+            .takes_value(true)
+            .value_name("N")
+            .possible_values(&["0", "1", "2", "3", "4", "5"])
+            .help("How much to scrub, from 0 (keep structure) to 5 (scrub everything), see --help")
+            .long_help(SCRUB_LEVEL_HELP))
+        .arg(clap::Arg::with_name("strict")
+            .long("strict")
+            .help("Refuse to run if the database has tables we don't recognize"))
+        .arg(clap::Arg::with_name("allow-unhandled-tables")
+            .long("allow-unhandled-tables")
+            .takes_value(true)
+            .value_name("TABLES")
+            .use_delimiter(true)
+            .multiple(true)
+            .help("Unrecognized tables that --strict should accept (comma separated)"))
+        .arg(clap::Arg::with_name("tables")
+            .long("tables")
+            .takes_value(true)
+            .value_name("TABLES")
+            .use_delimiter(true)
+            .multiple(true)
+            .help("Only anonymize these tables (comma separated), and copy the rest as they are"))
+        .arg(clap::Arg::with_name("exclude-tables")
+            .long("exclude-tables")
+            .takes_value(true)
+            .value_name("TABLES")
+            .use_delimiter(true)
+            .multiple(true)
+            .help("Copy these tables (comma separated) as they are, without anonymizing them. For \
+                   example, `--exclude-tables moz_bookmarks` keeps bookmark titles while still \
+                   anonymizing history"))
+        .arg(clap::Arg::with_name("max-runtime")
+            .long("max-runtime")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help("Give up (and remove the partial output) if anonymizing takes longer than this"))
+        .arg(clap::Arg::with_name("salvage")
+            .long("salvage")
+            .help("If the input is corrupt, anonymize what can still be read of it (skipping damaged \
+                   pages) rather than failing. What couldn't be recovered is logged"))
+        .arg(clap::Arg::with_name("thorough-verify")
+            .long("thorough-verify")
+            .help("After anonymizing, compare every cell of the output against the source, and check \
+                   both for corruption in full (including their indexes) rather than quickly. \
+                   This reads both databases in full, so it's slow on large profiles"))
+        .arg(clap::Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .value_name("SEED")
+            .help("Seed the random replacements with this number, so that anonymizing the same \
+                   database twice gives byte-identical output. Anyone who knows SEED can redo \
+                   the replacements, so don't use it for data you share"))
+        .arg(clap::Arg::with_name("key")
+            .long("key")
+            .takes_value(true)
+            .value_name("HEX")
+            .conflicts_with("seed")
+            .help("Derive each replacement from an HMAC of the original under this hex key, so \
+                   the same string gets the same replacement in every run with the same key. \
+                   Like --fingerprint-salt, this makes the output pseudonymous: anyone with the \
+                   key can check whether a given string is present"))
+        .arg(clap::Arg::with_name("mode")
+            .long("mode")
+            .takes_value(true)
+            .possible_values(&["random", "redact", "sequential", "hash", "fake", "stream"])
+            .default_value("random")
+            .help("How to replace text: with random text that looks like the original, with \
+                   the same fixed value, like <REDACTED>, for every value of a kind (redact, \
+                   which numbers values of UNIQUE columns to keep them unique), or with \
+                   numbered tokens like host-0001 or url-0421 (sequential), or with a truncated \
+                   SHA-256 HMAC of each value, under --key or a random key (hash), or with \
+                   realistic fake hosts, lorem ipsum titles and slugs for screenshots and demos \
+                   (fake), or with random text like the original derived from a keyed hash of it, \
+                   under --key or a random key, which remembers nothing so works for databases of \
+                   any size, but makes text shorter than 12 characters 12 long (stream)"))
+        .arg(clap::Arg::with_name("secure-rng")
+            .long("secure-rng")
+            .conflicts_with_all(&["seed", "key"])
+            .help("Seed every random choice straight from the operating system's RNG, failing if \
+                   it's unavailable rather than falling back to a weaker source of entropy. \
+                   Replacements come from a CSPRNG, so they're unpredictable, and can't be \
+                   derived from each other or from the originals"))
+        .arg(clap::Arg::with_name("keep-url-structure")
+            .long("keep-url-structure")
+            .help("Anonymize urls piece by piece (host labels, path segments, query keys and \
+                   values) so they still parse as urls of the same shape. Only then does each url \
+                   start with the prefix and host of its origin (in moz_origins); without it, urls \
+                   are replaced whole and have nothing in common with their origins"))
+        .arg(clap::Arg::with_name("regenerate-guids")
+            .long("regenerate-guids")
+            .help("Give places and bookmarks new random GUIDs that are still valid (12 characters \
+                   of base64url) and unique. The bookmark roots keep theirs"))
+        .arg(clap::Arg::with_name("allow-unknown-schema-version")
+            .long("allow-unknown-schema-version")
+            .help("Anonymize databases with a newer places schema than we know about, rather than \
+                   refusing to"))
+        .arg(clap::Arg::with_name("generic")
+            .long("generic")
+            .help("Anonymize any SQLite database, going only by its column names (urls, hosts, \
+                   titles, GUIDs, dates and so on) and --policy, without the rules for the \
+                   browser databases we know"))
+        .arg(clap::Arg::with_name("shift-dates")
+            .long("shift-dates")
+            .help("Move every date by the same random offset, of up to a year either way, so \
+                   they keep their order and the intervals between them but no longer say when \
+                   things happened"))
+        .arg(clap::Arg::with_name("round-dates")
+            .long("round-dates")
+            .takes_value(true)
+            .possible_values(&["hour", "day"])
+            .help("Truncate every date to the start of its hour or day"))
+        .arg(clap::Arg::with_name("count-noise")
+            .long("count-noise")
+            .takes_value(true)
+            .value_name("PERCENT")
+            .help("Scale visit counts, frecencies and other counters by a random factor within \
+                   this many percent either way, so exact counts can't identify anyone. They \
+                   stay positive and roughly in order"))
+        .arg(clap::Arg::with_name("bucket-lengths")
+            .long("bucket-lengths")
+            .takes_value(true)
+            .value_name("N")
+            .help("Pad replacement text to a multiple of N characters, so it doesn't give away the \
+                   exact length of the original. GUIDs, host labels and origin prefixes keep \
+                   their length"))
+        .arg(clap::Arg::with_name("keep-hosts")
+            .long("keep-hosts")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("File listing hosts (one per line, # for comments) to leave as they are, along \
+                   with their subdomains and the urls and origins on them. Page titles and \
+                   everything else are still anonymized"))
+        .arg(clap::Arg::with_name("keep-public-suffix")
+            .long("keep-public-suffix")
+            .help("Keep the public suffix of each host (like .com or .co.uk) and only anonymize \
+                   the domain and subdomains before it"))
+        .arg(clap::Arg::with_name("strip-query")
+            .long("strip-query")
+            .help("Remove the query string and fragment (where most personal data in urls hides) \
+                   from every url before anonymizing it"))
+        .arg(clap::Arg::with_name("keep-query-keys")
+            .long("keep-query-keys")
+            .requires("keep-url-structure")
+            .help("Keep the names of query parameters in urls, and only anonymize their values"))
+        .arg(clap::Arg::with_name("keep-title-structure")
+            .long("keep-title-structure")
+            .help("Anonymize titles word by word, keeping their spaces and punctuation, so they \
+                   have the same number of words of the same lengths"))
+        .arg(clap::Arg::with_name("policy")
+            .long("policy")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("TOML file of per-table and per-column policies (anonymize, null, keep, \
+                   delete-rows or hash) that override the built-in rules, like \
+                   `[moz_places]` then `title = \"keep\"`, or `moz_keywords = \"delete-rows\"` \
+                   for a whole table"))
+        .subcommand(clap::SubCommand::with_name("list-profiles")
+            .about("Lists the profiles we found, largest first")
+            .arg(clap::Arg::with_name("json")
+                .long("json")
+                .help("Print the profiles as JSON, with each one's path, schema version and size")))
+        .subcommand(clap::SubCommand::with_name("verify")
+            .about("Looks for every host, url and title in ORIGINAL anywhere in ANONYMIZED (including inside \
+                    urls and JSON), and prints where any turn up, failing if there were any")
+            .arg(clap::Arg::with_name("ORIGINAL")
+                .required(true)
+                .help("The database that was anonymized"))
+            .arg(clap::Arg::with_name("ANONYMIZED")
+                .required(true)
+                .help("Its anonymized copy"))
+            .arg(clap::Arg::with_name("keep-hosts")
+                .long("keep-hosts")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("The --keep-hosts file it was anonymized with, so the hosts in it (and their \
+                       subdomains, and the urls on them) aren't reported")))
     .get_matches();
 
     env_logger::init_from_env(match matches.occurrences_of("v") {
@@ -177,75 +898,314 @@ fn main() -> Result<()> {
         3 | _ => env_logger::Env::default().filter_or("RUST_LOG", "trace"),
     });
 
-    let profile = if let Some(places) = matches.value_of("PLACES") {
-        let meta = fs::metadata(&places)?;
-        Profile {
-            name: "".into(),
-            places_db: fs::canonicalize(places)?,
-            db_size: meta.len(),
+    if matches.is_present("post-to") && !cfg!(feature = "upload") {
+        bail!("--post-to requires anonymize-places to be built with the `upload` feature");
+    }
+
+    if matches.is_present("compare-profiles") {
+        let mut profiles = discover_profiles(&matches)?;
+        sort_profiles(&mut profiles);
+        compare_profiles(&profiles);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        let (original, anonymized) = (matches.value_of("ORIGINAL").unwrap(), matches.value_of("ANONYMIZED").unwrap());
+        let keep_hosts = read_keep_hosts(matches.value_of("keep-hosts"))?;
+        let leaks = find_leaks(Path::new(original), Path::new(anonymized), &keep_hosts)?;
+        for leak in &leaks {
+            println!("{} (rowid {}) has the {} {:?}", leak.column, leak.rowid, leak.kind, leak.value);
         }
+        if !leaks.is_empty() {
+            bail!("Found {} host(s), url(s) or title(s) from {:?} in {:?}", leaks.len(), original, anonymized);
+        }
+        println!("No hosts, urls or titles from {:?} found in {:?}", original, anonymized);
+        return Ok(());
+    }
+
+    if let Some(path) = matches.value_of("show-mapping") {
+        for (table, original, replacement) in mapping_entries(Path::new(path), &mapping_passphrase()?)? {
+            println!("{}\t{}\t{}", table, replacement, original);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("list-profiles") {
+        return list_profiles(matches);
+    }
+
+    let mut profiles = if let Some(places) = matches.value_of("PLACES") {
+        vec![Profile::for_db(Path::new(places))?]
     } else {
-        let mut profiles = get_profiles()?;
+        let mut profiles = discover_profiles(&matches)?;
         if profiles.len() == 0 {
             eprintln!("No profiles found!");
             bail!("No profiles found");
         }
-        profiles.sort_by(|a, b| b.db_size.cmp(&a.db_size));
+        sort_profiles(&mut profiles);
         for p in &profiles {
-            debug!("Found: {:?} with a {} places.sqlite", p.name, p.friendly_db_size())
+            debug!("Found: {:?} with a {} places.sqlite", p.qualified_name(), p.friendly_db_size())
         }
-        println!("Using profile {:?}", profiles[0].name);
-        profiles.into_iter().next().unwrap()
-    };
-
-    let output_path = Path::new(matches.value_of("OUTPUT")
-        .unwrap_or_else(|| "./places_anonymized.sqlite".into()));
-    if output_path.exists() {
-        if matches.is_present("force") {
-            fs::remove_file(&output_path)?;
+        if matches.is_present("all-profiles") {
+            profiles
         } else {
-            eprintln!("Error: {} already exists but `-f` argument was not provided", output_path.to_str().unwrap());
-            bail!("File already exists");
+            let profile = if let Some(name) = matches.value_of("profile") {
+                find_profile(profiles, name)?
+            } else {
+                // The default Firefox profile, or else the default profile of a
+                // Firefox fork, or else the largest, if there are any.
+                let firefox = profiles.iter().position(|p| p.is_default && p.install == "Firefox")
+                    .or_else(|| profiles.iter().position(|p| p.is_default && !p.is_chromium))
+                    .or_else(|| profiles.iter().position(|p| !p.is_chromium))
+                    .unwrap_or(0);
+                if profiles.len() > 1 && !matches.is_present("non-interactive") && std::io::stdin().is_terminal() {
+                    pick_profile(profiles, firefox)?
+                } else {
+                    profiles.swap_remove(firefox)
+                }
+            };
+            println!("Using profile {:?}", profile.qualified_name());
+            vec![profile]
         }
+    };
+
+    let max_runtime = match matches.value_of("max-runtime") {
+        Some(s) => Some(Duration::from_secs(s.parse::<u64>().map_err(|e|
+            format_err!("Bad --max-runtime {:?}: {}", s, e))?)),
+        None => None,
+    };
+
+    let min_size_reduction = match matches.value_of("assert-size-reduction") {
+        Some(p) => Some(p.parse::<f64>().map_err(|e|
+            format_err!("Bad --assert-size-reduction {:?}: {}", p, e))?),
+        None => None,
+    };
+
+    let only_schema_version = match matches.value_of("only-schema-version") {
+        Some(v) => Some(v.parse::<i64>().map_err(|e|
+            format_err!("Bad --only-schema-version {:?}: {}", v, e))?),
+        None => None,
+    };
+
+    let seed = match matches.value_of("seed") {
+        Some(s) => Some(s.parse::<u64>().map_err(|e| format_err!("Bad --seed {:?}: {}", s, e))?),
+        None => None,
+    };
+
+    let key = match matches.value_of("key") {
+        Some(k) => Some(parse_hex(k).ok_or_else(|| format_err!("Bad --key {:?}: expected hex", k))?),
+        None => None,
+    };
+
+    let policies = match matches.value_of("policy") {
+        Some(path) => Policies::from_file(Path::new(path))?,
+        None => Policies::default(),
+    };
+
+    let count_noise = match matches.value_of("count-noise") {
+        Some(p) => Some(p.parse::<f64>().map_err(|e| format_err!("Bad --count-noise {:?}: {}", p, e))?),
+        None => None,
+    };
+
+    let length_bucket = match matches.value_of("bucket-lengths") {
+        Some(n) => Some(n.parse::<usize>().map_err(|e| format_err!("Bad --bucket-lengths {:?}: {}", n, e))?),
+        None => None,
+    };
+
+    let batch_size = match matches.value_of("batch-size") {
+        Some(n) => match n.parse::<u64>() {
+            Ok(0) => bail!("--batch-size must be at least 1"),
+            Ok(n) => Some(n),
+            Err(e) => bail!("Bad --batch-size {:?}: {}", n, e),
+        },
+        None => None,
+    };
+    if batch_size.is_some() && matches.value_of("engine") != Some("rows") {
+        bail!("--batch-size only works with --engine rows");
     }
 
-    fs::copy(&profile.places_db, &output_path)?;
-    let anon_places = Connection::open_with_flags(&output_path,
-        OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let threads = match matches.value_of("threads") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(0) => bail!("--threads must be at least 1"),
+            Ok(n) => n,
+            Err(e) => bail!("Bad --threads {:?}: {}", n, e),
+        },
+        None => 1,
+    };
 
-    {
-        let mut anonymizer = StringAnonymizer::default();
-        anon_places.create_scalar_function("anonymize", 1, true, move |ctx| {
-            let arg = ctx.get::<rusqlite::types::Value>(0)?;
-            Ok(match arg {
-                rusqlite::types::Value::Text(s) =>
-                    rusqlite::types::Value::Text(anonymizer.anonymize(&s)),
-                not_text => not_text
-            })
-        })?;
+    let memory_budget = match matches.value_of("max-memory") {
+        Some(mb) => Some(mb.parse::<usize>().map_err(|e| format_err!("Bad --max-memory {:?}: {}", mb, e))? << 20),
+        None => None,
+    };
+
+    let keep_hosts = read_keep_hosts(matches.value_of("keep-hosts"))?;
+
+    let export_mapping = matches.value_of("export-mapping").map(PathBuf::from);
+    let import_mapping = matches.value_of("import-mapping").map(PathBuf::from);
+    let mapping_passphrase = if export_mapping.is_some() || import_mapping.is_some() {
+        Some(mapping_passphrase()?)
+    } else {
+        None
+    };
+
+    let options = Options {
+        anonymize_downloads: matches.is_present("anonymize-downloads"),
+        fingerprint_salt: matches.value_of("fingerprint-salt").map(|s| s.into()),
+        scrub_level: match matches.value_of("scrub-level") {
+            Some(level) => level.parse().unwrap(),
+            None => Options::default().scrub_level,
+        },
+        strict: matches.is_present("strict"),
+        allowed_tables: matches.values_of("allow-unhandled-tables")
+            .map(|v| v.map(|t| t.into()).collect())
+            .unwrap_or_default(),
+        max_runtime,
+        salvage: matches.is_present("salvage"),
+        thorough_verify: matches.is_present("thorough-verify"),
+        optimize: matches.is_present("optimize"),
+        vacuum: !matches.is_present("no-vacuum"),
+        min_size_reduction,
+        only_schema_version,
+        seed,
+        key,
+        keep_url_structure: matches.is_present("keep-url-structure"),
+        regenerate_guids: matches.is_present("regenerate-guids"),
+        allow_unknown_schema_version: matches.is_present("allow-unknown-schema-version"),
+        generic: matches.is_present("generic"),
+        policies,
+        tables: matches.values_of("tables").map(|v| v.map(|t| t.into()).collect()),
+        exclude_tables: matches.values_of("exclude-tables")
+            .map(|v| v.map(|t| t.into()).collect())
+            .unwrap_or_default(),
+        shift_dates: matches.is_present("shift-dates"),
+        count_noise,
+        keep_hosts,
+        keep_public_suffix: matches.is_present("keep-public-suffix"),
+        strip_query: matches.is_present("strip-query"),
+        keep_query_keys: matches.is_present("keep-query-keys"),
+        keep_title_structure: matches.is_present("keep-title-structure"),
+        length_bucket,
+        secure_rng: matches.is_present("secure-rng"),
+        mode: match matches.value_of("mode") {
+            Some("redact") => Mode::Redact,
+            Some("sequential") => Mode::Sequential,
+            Some("hash") => Mode::Hash,
+            Some("fake") => Mode::Fake,
+            Some("stream") => Mode::Stream,
+            _ => Mode::Random,
+        },
+        export_mapping,
+        import_mapping,
+        mapping_passphrase,
+        strip_favicons: matches.is_present("strip-favicons"),
+        hash_cookie_values: matches.is_present("hash-cookie-values"),
+        dry_run: matches.is_present("dry-run"),
+        stats: matches.is_present("stats") || matches.is_present("json-summary"),
+        progress: !matches.is_present("no-progress") && std::io::stderr().is_terminal(),
+        engine: match matches.value_of("engine") {
+            Some("rows") => Engine::Rows,
+            _ => Engine::Sql,
+        },
+        cancel: cancel_on_ctrl_c(),
+        batch_size,
+        fast_writes: !matches.is_present("safe-writes"),
+        threads,
+        memory_budget,
+        round_dates: match matches.value_of("round-dates") {
+            Some("hour") => Some(Duration::from_secs(60 * 60)),
+            Some(_) => Some(Duration::from_secs(24 * 60 * 60)),
+            None => None,
+        },
+    };
+    let options_json = options_summary(&options);
+    let started = Instant::now();
+    let mut summary = vec![];
+
+    if options.thorough_verify {
+        println!("Verifying every cell against the source once done, this may take a while...");
     }
 
-    let schema = {
-        let mut stmt = anon_places.prepare("
-            SELECT name FROM sqlite_master
-            WHERE type = 'table'
-              AND name NOT LIKE 'sqlite_%' -- ('sqlite_sequence', 'sqlite_stat1', 'sqlite_master', anyt)
-        ")?;
-        let mut rows = stmt.query(&[])?;
-        let mut tables = vec![];
-        while let Some(row_or_error) = rows.next() {
-            tables.push(TableInfo::for_table(row_or_error?.get("name"), &anon_places)?);
+    if matches.is_present("all-profiles") {
+        let result = anonymize_all_profiles(&profiles, &matches, options, &mut summary);
+        write_summary(&matches, &options_json, &summary, started)?;
+        return result;
+    }
+    let profile = profiles.pop().expect("We picked a profile");
+    check_not_running(&profile, &matches, options.cancel.as_deref())?;
+    let mut others = matches.values_of("PLACES")
+        .map(|v| v.skip(1).map(PathBuf::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if matches.is_present("session-store") {
+        let profile_dir = profile.places_db.parent().unwrap_or_else(|| Path::new("."));
+        let files = session_store_files(profile_dir)?;
+        if files.is_empty() {
+            warn!("No session store files in {:?}", profile_dir);
         }
-        tables
-    };
+        others.extend(files);
+    }
+    if !others.is_empty() {
+        if matches.is_present("post-to") {
+            bail!("--post-to can only upload a single database");
+        }
+        let dir = PathBuf::from(matches.value_of("OUTPUT").unwrap_or("./places_anonymized"));
+        if !options.dry_run {
+            fs::create_dir_all(&dir).map_err(|e| format_err!("Can't create output directory {:?}: {}", dir, e))?;
+        }
+        let session = Session::new(options)?;
+        for input in Some(&profile.places_db).into_iter().chain(&others) {
+            let name = input.file_name().ok_or_else(|| format_err!("{:?} isn't a file", input))?;
+            let output_path = check_output_path(dir.join(name), &matches)?;
+            println!("Anonymizing {:?} to {:?}", input, output_path);
+            let input_started = Instant::now();
+            let outcome = session.anonymize(input, &output_path);
+            summary.push(database_summary(input, &output_path, &outcome, input_started.elapsed()));
+            if outcome.is_err() {
+                write_summary(&matches, &options_json, &summary, started)?;
+            }
+            match outcome? {
+                Outcome::Anonymized { tables, .. } if matches.is_present("stats") => print_stats(&tables),
+                Outcome::Anonymized { .. } => {}
+                Outcome::Skipped { schema_version } => {
+                    println!("Skipping {:?}: schema version {} doesn't match --only-schema-version",
+                             input, schema_version);
+                }
+                Outcome::DryRun(plan) => print_dry_run(&plan),
+            }
+        }
+        write_summary(&matches, &options_json, &summary, started)?;
+        println!("Done!");
+        return Ok(());
+    }
+
+    let output_path = check_output_path(PathBuf::from(matches.value_of("OUTPUT")
+        .unwrap_or("./places_anonymized.sqlite")), &matches)?;
+    let outcome = anonymize_places::anonymize_places(&profile.places_db, &output_path, &options);
+    summary.push(database_summary(&profile.places_db, &output_path, &outcome, started.elapsed()));
+    write_summary(&matches, &options_json, &summary, started)?;
+    match outcome? {
+        Outcome::Anonymized { tables, .. } if matches.is_present("stats") => print_stats(&tables),
+        Outcome::Anonymized { .. } => {}
+        Outcome::Skipped { schema_version } => {
+            println!("Skipping {:?}: schema version {} doesn't match --only-schema-version {}",
+                     profile.places_db, schema_version, options.only_schema_version.unwrap());
+            return Ok(());
+        }
+        Outcome::DryRun(plan) => {
+            print_dry_run(&plan);
+            println!("Dry run, so nothing was written");
+            return Ok(());
+        }
+    }
+    if options.thorough_verify {
+        println!("Verification passed");
+    }
 
-    for info in schema {
-        let sql = info.make_update("anonymize");
-        debug!("Executing sql:\n{}", sql);
-        anon_places.execute(&sql, &[])?;
+    if let Some(url) = matches.value_of("post-to") {
+        let headers = matches.values_of("post-header")
+            .map(|v| v.collect::<Vec<_>>())
+            .unwrap_or_default();
+        upload(&output_path, url, &headers, matches.value_of("post-auth"))?;
     }
-    debug!("Clearing places url_hash");
-    anon_places.execute("UPDATE moz_places SET url_hash = 0", &[])?;
     println!("Done!");
 
     Ok(())
@@ -0,0 +1,137 @@
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use ring::{aead, pbkdf2};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{self, Value};
+
+use Result;
+
+// Mapping files start with this, then the salt and the nonce, then the JSON
+// mapping encrypted with AES-256-GCM under a key derived from the passphrase.
+const MAGIC: &[u8] = b"APMAP\0\0\x01";
+const SALT_LEN: usize = 16;
+// As OWASP recommends for PBKDF2-HMAC-SHA256.
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> aead::LessSafeKey {
+    let mut key = [0; 32];
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).expect("Iterations aren't zero");
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_256_GCM, &key).expect("The key is the right length"))
+}
+
+// Writes `mapping` to `path`, encrypted with `passphrase`.
+pub(crate) fn write_mapping(path: &Path, mapping: &Value, passphrase: &str) -> Result<()> {
+    let random = SystemRandom::new();
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; aead::NONCE_LEN];
+    random.fill(&mut salt).map_err(|_| format_err!(Other, "Can't get random bytes for the mapping's salt"))?;
+    random.fill(&mut nonce).map_err(|_| format_err!(Other, "Can't get random bytes for the mapping's nonce"))?;
+    let mut data = serde_json::to_vec(mapping).map_err(|e| format_err!(Other, "Can't encode the mapping: {}", e))?;
+    derive_key(passphrase, &salt)
+        .seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::from(MAGIC), &mut data)
+        .map_err(|_| format_err!(Other, "Can't encrypt the mapping"))?;
+    let mut file = MAGIC.to_vec();
+    file.extend_from_slice(&salt);
+    file.extend_from_slice(&nonce);
+    file.extend_from_slice(&data);
+    fs::write(path, file).map_err(|e| format_err!(Other, "Can't write mapping {:?}: {}", path, e))?;
+    Ok(())
+}
+
+// Reads a mapping written by `write_mapping`.
+pub(crate) fn read_mapping(path: &Path, passphrase: &str) -> Result<Value> {
+    let file = fs::read(path).map_err(|e| format_err!(BadFile, "Can't read mapping {:?}: {}", path, e))?;
+    if !file.starts_with(MAGIC) || file.len() < MAGIC.len() + SALT_LEN + aead::NONCE_LEN {
+        bail!(BadFile, "{:?} isn't a mapping file", path);
+    }
+    let (salt, rest) = file[MAGIC.len()..].split_at(SALT_LEN);
+    let (nonce, data) = rest.split_at(aead::NONCE_LEN);
+    let mut data = data.to_vec();
+    let nonce = aead::Nonce::try_assume_unique_for_key(nonce).expect("The nonce is the right length");
+    let json = derive_key(passphrase, salt)
+        .open_in_place(nonce, aead::Aad::from(MAGIC), &mut data)
+        .map_err(|_| format_err!(BadFile, "Can't decrypt mapping {:?}: wrong passphrase, or the file is damaged", path))?;
+    serde_json::from_slice(json).map_err(|e| format_err!(BadFile, "Mapping {:?} is damaged: {}", path, e))
+}
+
+/// Reads a mapping written with [`Options::export_mapping`](::Options), and
+/// returns each replacement in it as the table it's from (like `strings`, or
+/// `fakes/host`), the original, and what it was replaced with.
+pub fn mapping_entries(path: &Path, passphrase: &str) -> Result<Vec<(String, String, String)>> {
+    let mut entries = vec![];
+    for (table, value) in read_mapping(path, passphrase)?.as_object().into_iter().flatten() {
+        for (key, value) in value.as_object().into_iter().flatten() {
+            match *value {
+                Value::Object(ref kind) => for (original, replacement) in kind {
+                    entries.push((format!("{}/{}", table, key), original.clone(), plain(replacement)));
+                },
+                ref replacement => entries.push((table.clone(), key.clone(), plain(replacement))),
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Strings without their quotes, and anything else (like numbers) as JSON.
+fn plain(value: &Value) -> String {
+    value.as_str().map(String::from).unwrap_or_else(|| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use Error;
+
+    fn written(dir: &Path) -> (::std::path::PathBuf, Value) {
+        let path = dir.join("mapping");
+        let mapping = json!({"strings": {"example.com": "qzwxrty.vbn"}, "fakes": {"host": {"a.org": "blue-otter.example"}}});
+        write_mapping(&path, &mapping, "secret").unwrap();
+        (path, mapping)
+    }
+
+    fn assert_bad_file(result: Result<Value>) {
+        match result {
+            Err(Error::BadFile(_)) => {}
+            other => panic!("Expected a BadFile error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, mapping) = written(dir.path());
+        assert!(!fs::read(&path).unwrap().windows(11).any(|w| w == b"example.com"));
+        assert_eq!(read_mapping(&path, "secret").unwrap(), mapping);
+        let mut entries = mapping_entries(&path, "secret").unwrap();
+        entries.sort();
+        assert_eq!(entries, [
+            ("fakes/host".into(), "a.org".into(), "blue-otter.example".into()),
+            ("strings".into(), "example.com".into(), "qzwxrty.vbn".into()),
+        ]);
+    }
+
+    #[test]
+    fn wrong_passphrases_and_damage_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = written(dir.path());
+        assert_bad_file(read_mapping(&path, "wrong"));
+        let file = fs::read(&path).unwrap();
+
+        let mut tampered = file.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        fs::write(&path, &tampered).unwrap();
+        assert_bad_file(read_mapping(&path, "secret"));
+
+        // Cut off in the ciphertext, and in the header.
+        for &len in &[file.len() - 20, MAGIC.len() + 4, 3] {
+            fs::write(&path, &file[..len]).unwrap();
+            assert_bad_file(read_mapping(&path, "secret"));
+        }
+        assert_bad_file(read_mapping(&dir.path().join("missing"), "secret"));
+    }
+}
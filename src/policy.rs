@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use toml;
+
+use Result;
+
+// Policies for tables (mostly of the other databases we know) where the rules
+// for a column of its name would be wrong. Any the user gives take precedence.
+const BUILTIN_POLICIES: &str = r#"
+# Chrome's History keeps its schema version and the like in meta.
+meta = "keep"
+# And Safari's History.db in metadata.
+metadata = "keep"
+
+# The names places keeps its own bookkeeping under, like Firefox for Android's
+# sync ids and times.
+[moz_meta]
+key = "keep"
+
+# The names of the form fields, like `searchbar-history` or `email`, which say
+# what the (anonymized) values are.
+[moz_formhistory]
+fieldname = "keep"
+
+# Permission types, like `geo` or `cookie`, which mean nothing once anonymized.
+[moz_perms]
+type = "keep"
+
+[moz_hosts]
+type = "keep"
+
+# Content pref names, like `browser.content.full-zoom`, and their values, which
+# are mostly just numbers.
+[settings]
+name = "keep"
+
+[prefs]
+value = "keep"
+
+[downloads]
+mime_type = "keep"
+original_mime_type = "keep"
+http_method = "keep"
+
+# What Safari's History.db autocompletes each url for, which is what was typed.
+[history_items]
+autocomplete_triggers = "null"
+
+# Safari's own names for the parts of it that listen for history changes.
+[history_event_listeners]
+listener_name = "keep"
+
+[history_events]
+event_type = "keep"
+pending_listeners = "keep"
+"#;
+
+/// What to do with a column (or every column of a table), overriding the
+/// built-in rules.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Anonymize it like any other text, whatever kind of column it is.
+    Anonymize,
+    /// Set it to NULL.
+    Null,
+    /// Leave it exactly as it is.
+    Keep,
+    /// Delete the rows where it isn't NULL (or every row, for a table).
+    DeleteRows,
+    /// Replace it with a keyed hash of it, as [`Mode::Hash`](::Mode) does:
+    /// 32 hex digits, the same for equal values, that can't be matched
+    /// against guesses without the key. Numbers are hashed too, and NULLs
+    /// stay NULL.
+    Hash,
+}
+
+impl Policy {
+    fn parse(s: &str) -> Result<Policy> {
+        Ok(match s {
+            "anonymize" => Policy::Anonymize,
+            "null" => Policy::Null,
+            "keep" => Policy::Keep,
+            "delete-rows" => Policy::DeleteRows,
+            "hash" => Policy::Hash,
+            _ => bail!(BadFile, "Unknown policy {:?}, expected anonymize, null, keep, delete-rows or hash", s),
+        })
+    }
+}
+
+/// Per-table and per-column policies, usually read from a TOML file like:
+///
+/// ```toml
+/// # Every column of moz_keywords is kept.
+/// moz_keywords = "keep"
+///
+/// [moz_places]
+/// title = "keep"
+/// description = "null"
+/// ```
+///
+/// A column's own policy takes precedence over its table's.
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    tables: HashMap<String, Policy>,
+    columns: HashMap<(String, String), Policy>,
+}
+
+impl Policies {
+    pub fn from_file(path: &Path) -> Result<Policies> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!(BadFile, "Can't read policy file {:?}: {}", path, e))?;
+        Policies::parse(&text).map_err(|e| format_err!(BadFile, "Bad policy file {:?}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Policies> {
+        let value = text.parse::<toml::Value>().map_err(|e| format_err!(BadFile, "{}", e))?;
+        let mut policies = Policies::default();
+        for (table, entry) in value.as_table().into_iter().flat_map(|t| t.iter()) {
+            match *entry {
+                toml::Value::String(ref s) => {
+                    policies.tables.insert(table.clone(), Policy::parse(s)?);
+                }
+                toml::Value::Table(ref cols) => for (col, policy) in cols {
+                    let policy = policy.as_str()
+                        .ok_or_else(|| format_err!(BadFile, "Policy for {}.{} isn't a string", table, col))?;
+                    policies.columns.insert((table.clone(), col.clone()), Policy::parse(policy)?);
+                },
+                _ => bail!(BadFile, "Policy for {} should be a string or a table of columns", table),
+            }
+        }
+        Ok(policies)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty() && self.columns.is_empty()
+    }
+
+    // These policies on top of the built-in ones.
+    pub(crate) fn with_builtin(&self) -> Policies {
+        let mut merged = Policies::parse(BUILTIN_POLICIES).expect("The built-in policies parse");
+        // A table's policy takes precedence over built-in ones for its columns.
+        merged.columns.retain(|(table, _), _| !self.tables.contains_key(table));
+        merged.tables.extend(self.tables.iter().map(|(t, p)| (t.clone(), *p)));
+        merged.columns.extend(self.columns.iter().map(|(c, p)| (c.clone(), *p)));
+        merged
+    }
+
+    pub(crate) fn for_column(&self, table: &str, col: &str) -> Option<Policy> {
+        self.columns.get(&(table.to_owned(), col.to_owned()))
+            .or_else(|| self.tables.get(table))
+            .cloned()
+    }
+
+    pub(crate) fn for_table(&self, table: &str) -> Option<Policy> {
+        self.tables.get(table).cloned()
+    }
+
+    // Every table the policies name, with its columns that have their own policy.
+    pub(crate) fn named(&self) -> Vec<(&str, Option<&str>)> {
+        let mut named = self.tables.keys().map(|t| (t.as_str(), None))
+            .chain(self.columns.keys().map(|(t, c)| (t.as_str(), Some(c.as_str()))))
+            .collect::<Vec<_>>();
+        named.sort();
+        named
+    }
+
+    // An SQL expression that's true for the rows of `table` to delete, if any.
+    pub(crate) fn deletion(&self, table: &str) -> Option<String> {
+        if self.for_table(table) == Some(Policy::DeleteRows) {
+            return Some("1".into());
+        }
+        let mut conds = self.columns.iter()
+            .filter(|&((t, _), &p)| t == table && p == Policy::DeleteRows)
+            .map(|((_, col), _)| format!("{} IS NOT NULL", col))
+            .collect::<Vec<_>>();
+        conds.sort();
+        if conds.is_empty() { None } else { Some(conds.join(" OR ")) }
+    }
+}
@@ -0,0 +1,501 @@
+use std::{process, fs, path::{Path, PathBuf}};
+use std::collections::HashMap;
+
+use Result;
+use schema::schema_version;
+use snapshot::{open_source, sidecar};
+
+/// A Firefox profile that has a places database, or a Chromium based
+/// browser's profile that has a History database.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub install: String,
+    pub name: String,
+    /// The history database: places.sqlite, or History for Chromium based
+    /// browsers.
+    pub places_db: PathBuf,
+    pub db_size: u64,
+    pub is_chromium: bool,
+    /// Whether Firefox's profiles.ini (or installs.ini) says this is the
+    /// default profile.
+    pub is_default: bool,
+}
+
+impl Profile {
+    /// A profile for a places.sqlite that wasn't found through discovery.
+    pub fn for_db(places_db: &Path) -> Result<Profile> {
+        let meta = fs::metadata(places_db)?;
+        Ok(Profile {
+            install: "".into(),
+            name: "".into(),
+            places_db: fs::canonicalize(places_db)?,
+            db_size: meta.len(),
+            is_chromium: false,
+            is_default: false,
+        })
+    }
+
+    /// The `install/name` form used to disambiguate profiles that share a name
+    /// across different Firefox installs.
+    pub fn qualified_name(&self) -> String {
+        format!("{}/{}", self.install, self.name)
+    }
+
+    /// The schema version of the profile's history database (its
+    /// `user_version`).
+    pub fn schema_version(&self) -> Result<i64> {
+        schema_version(&open_source(&self.places_db)?)
+    }
+
+    /// Whether the browser seems to have the profile open, in which case a
+    /// copy of its database may be missing changes it hasn't written yet.
+    /// Firefox locks the profile's `.parentlock` (`parent.lock` on Windows)
+    /// while it runs. Without a lock file to go by, a `-wal` with anything in
+    /// it means the database is probably open, as it's emptied on close.
+    pub fn in_use(&self) -> bool {
+        let dir = self.places_db.parent().unwrap_or_else(|| Path::new("."));
+        if let Some(held) = parent_lock_held(dir) {
+            return held;
+        }
+        fs::metadata(sidecar(&self.places_db, "-wal")).map(|meta| meta.len() > 0).unwrap_or(false)
+    }
+
+    pub fn friendly_db_size(&self) -> String {
+        let sizes = [
+            (1024 * 1024 * 1024, "Gb"),
+            (1024 * 1024, "Mb"),
+            (1024, "Kb"),
+        ];
+        for (lim, suffix) in &sizes {
+            if self.db_size >= *lim {
+
+                return format!("~{} {}", ((self.db_size as f64 / *lim as f64) * 10.0).round() / 10.0, suffix);
+            }
+        }
+        format!("{} bytes", self.db_size)
+    }
+}
+
+type Dirs = &'static [&'static str];
+
+// Chromium based browsers, and where each keeps its profiles (its "User Data"
+// directory) on Windows (under AppData/Local), macOS (under Application
+// Support) and other unix likes (under ~/.config).
+const CHROMIUM_BROWSERS: &[(&str, Dirs, Dirs, Dirs)] = &[
+    ("Chrome", &["Google", "Chrome", "User Data"], &["Google", "Chrome"], &["google-chrome"]),
+    ("Chromium", &["Chromium", "User Data"], &["Chromium"], &["chromium"]),
+    ("Edge", &["Microsoft", "Edge", "User Data"], &["Microsoft Edge"], &["microsoft-edge"]),
+    ("Brave", &["BraveSoftware", "Brave-Browser", "User Data"], &["BraveSoftware", "Brave-Browser"],
+     &["BraveSoftware", "Brave-Browser"]),
+    ("Vivaldi", &["Vivaldi", "User Data"], &["Vivaldi"], &["vivaldi"]),
+];
+
+// Browsers built from Firefox that keep their profiles (with profiles.ini)
+// somewhere of their own, and where under the home directory that is on
+// Windows, macOS and other unix likes. Tor Browser is portable, so these are
+// where it's usually unpacked (or, on Linux, installed by its launcher).
+const FIREFOX_FORKS: &[(&str, Dirs, Dirs, Dirs)] = &[
+    ("LibreWolf", &["AppData", "Roaming", "librewolf", "Profiles"],
+     &["Library", "Application Support", "librewolf", "Profiles"], &[".librewolf"]),
+    ("Waterfox", &["AppData", "Roaming", "Waterfox", "Profiles"],
+     &["Library", "Application Support", "Waterfox", "Profiles"], &[".waterfox"]),
+    ("Tor Browser", &["Desktop", "Tor Browser", "Browser", "TorBrowser", "Data", "Browser"],
+     &["Library", "Application Support", "TorBrowser-Data", "Browser"],
+     &[".local", "share", "torbrowser", "tbb", "x86_64", "tor-browser", "Browser", "TorBrowser", "Data", "Browser"]),
+];
+
+// Returns the `(install, path, is_chromium)` triples for every directory we
+// search for profiles.
+fn profile_roots() -> Result<Vec<(String, PathBuf, bool)>> {
+    let home = match dirs::home_dir() {
+        Some(dir) => dir,
+        None => bail!(ProfileDiscovery, "No home directory found!")
+    };
+    let mut path = home.clone();
+    let mut roots = vec![];
+    let mut firefox_roots = vec![];
+    if cfg!(windows) {
+        path.extend(&["AppData", "Roaming", "Mozilla", "Firefox", "Profiles"]);
+        for &(browser, dirs, _, _) in FIREFOX_FORKS {
+            let mut root = home.clone();
+            root.extend(dirs);
+            firefox_roots.push((browser.into(), root, false));
+        }
+        for &(browser, dirs, _, _) in CHROMIUM_BROWSERS {
+            let mut root = home.join("AppData").join("Local");
+            root.extend(dirs);
+            roots.push((browser.into(), root, true));
+        }
+    } else {
+        let out = String::from_utf8(process::Command::new("uname").args(&["-s"]).output()?.stdout)
+            .map_err(|e| format_err!(ProfileDiscovery, "Can't tell what system this is: {}", e))?;
+        debug!("Uname says: {:?}", out);
+        let is_mac = out.trim() == "Darwin";
+        if is_mac {
+            // ~/Library/Application Support/Firefox/Profiles
+            path.extend(&["Library", "Application Support", "Firefox", "Profiles"]);
+        } else {
+            // I'm not actually sure if this is true for all non-macos unix likes.
+            path.extend(&[".mozilla", "firefox"]);
+            // Ubuntu's Snap and Flatpak each keep the profiles of their Firefox
+            // in a home directory of its own.
+            let snap = home.join("snap").join("firefox").join("common");
+            let flatpak = home.join(".var").join("app").join("org.mozilla.firefox");
+            for &(install, ref dir) in &[("Firefox Snap", snap), ("Firefox Flatpak", flatpak)] {
+                firefox_roots.push((install.into(), dir.join(".mozilla").join("firefox"), false));
+            }
+        }
+        for &(browser, _, mac_dirs, unix_dirs) in FIREFOX_FORKS {
+            let mut root = home.clone();
+            root.extend(if is_mac { mac_dirs } else { unix_dirs });
+            firefox_roots.push((browser.into(), root, false));
+        }
+        for &(browser, _, mac_dirs, unix_dirs) in CHROMIUM_BROWSERS {
+            let root = if is_mac {
+                let mut root = home.join("Library").join("Application Support");
+                root.extend(mac_dirs);
+                root
+            } else {
+                let mut root = home.join(".config");
+                root.extend(unix_dirs);
+                root
+            };
+            roots.push((browser.into(), root, true));
+        }
+    }
+    firefox_roots.insert(0, ("Firefox".into(), path, false));
+    firefox_roots.extend(roots);
+    Ok(firefox_roots)
+}
+
+// Whether another process holds the lock on Firefox's `.parentlock` in
+// `profile_dir`, or None if there isn't one. The file outlives Firefox, so
+// it's the lock that counts.
+#[cfg(unix)]
+pub(crate) fn parent_lock_held(profile_dir: &Path) -> Option<bool> {
+    use std::os::unix::io::AsRawFd;
+    let file = fs::File::open(profile_dir.join(".parentlock")).ok()?;
+    let mut lock: libc::flock = unsafe { ::std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } == -1 {
+        return None;
+    }
+    Some(libc::c_int::from(lock.l_type) != libc::F_UNLCK)
+}
+
+// Firefox opens `parent.lock` without sharing it while it runs, so we can't
+// open it until Firefox closes.
+#[cfg(not(unix))]
+pub(crate) fn parent_lock_held(profile_dir: &Path) -> Option<bool> {
+    let path = profile_dir.join("parent.lock");
+    if !path.exists() {
+        return None;
+    }
+    Some(fs::File::open(path).is_err())
+}
+
+// Whether `dir` looks like a browser profile: it has Firefox's lock file or
+// prefs, or Chromium's Preferences next to its History.
+pub(crate) fn is_profile_dir(dir: &Path) -> bool {
+    [".parentlock", "parent.lock", "prefs.js"].iter().any(|name| dir.join(name).exists()) ||
+        (dir.join("Preferences").is_file() && dir.join("History").is_file())
+}
+
+/// Finds every profile with a places.sqlite in the usual Firefox locations
+/// (including those of the Snap and Flatpak packages on Linux) and those of
+/// LibreWolf, Waterfox and Tor Browser,
+/// and every profile with a History database in the usual locations of
+/// Chromium based browsers (Chrome, Chromium, Edge, Brave and Vivaldi).
+pub fn get_profiles() -> Result<Vec<Profile>> {
+    let mut res = vec![];
+    for (install, path, is_chromium) in profile_roots()? {
+        // Firefox lists its profiles in profiles.ini, which is next to the
+        // Profiles directory (or in the same directory, on Linux), and can
+        // name profiles anywhere. We only look for them ourselves without it.
+        let ini_dir = if path.ends_with("Profiles") { path.parent().unwrap_or(&path) } else { &path };
+        if !is_chromium && ini_dir.join("profiles.ini").is_file() {
+            res.extend(get_profiles_from_ini(&install, ini_dir)?);
+            continue;
+        }
+        if !path.is_dir() {
+            debug!("Skipping missing profile path: {:?}", path);
+            continue;
+        }
+        res.extend(get_profiles_in(&install, &path, is_chromium)?);
+    }
+    Ok(res)
+}
+
+/// Finds the profiles in `dir`, which is somewhere we wouldn't look on our own,
+/// like a portable install or a backup: a directory with a profiles.ini, a
+/// directory of profiles, or a single profile. Its profiles are in the
+/// `Custom` install.
+pub fn get_profiles_in_dir(dir: &Path) -> Result<Vec<Profile>> {
+    if !dir.is_dir() {
+        bail!(ProfileDiscovery, "Profile directory {:?} doesn't exist", dir);
+    }
+    if dir.join("profiles.ini").is_file() {
+        return get_profiles_from_ini("Custom", dir);
+    }
+    for &(db, is_chromium) in &[("places.sqlite", false), ("History", true)] {
+        if dir.join(db).is_file() {
+            let mut profile = Profile::for_db(&dir.join(db))?;
+            profile.install = "Custom".into();
+            profile.name = dir.file_name().and_then(|n| n.to_str()).unwrap_or("profile").into();
+            profile.is_chromium = is_chromium;
+            return Ok(vec![profile]);
+        }
+    }
+    let mut res = get_profiles_in("Custom", dir, false)?;
+    res.extend(get_profiles_in("Custom", dir, true)?);
+    Ok(res)
+}
+
+// The sections of an ini file, in order, each with its keys and values.
+fn parse_ini(text: &str) -> Vec<(String, HashMap<String, String>)> {
+    let mut sections: Vec<(String, HashMap<String, String>)> = vec![];
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') && line.ends_with(']') {
+            sections.push((line[1..line.len() - 1].into(), HashMap::new()));
+        } else if let (Some(i), Some(section)) = (line.find('='), sections.last_mut()) {
+            if !line.starts_with(';') && !line.starts_with('#') {
+                section.1.insert(line[..i].trim().into(), line[i + 1..].trim().into());
+            }
+        }
+    }
+    sections
+}
+
+// The profiles listed in the profiles.ini in `dir` that have a places.sqlite.
+// The default is the one installs.ini (or an Install section of profiles.ini)
+// names, as each Firefox install has had its own since Firefox 67, or else
+// the one marked Default=1.
+fn get_profiles_from_ini(install: &str, dir: &Path) -> Result<Vec<Profile>> {
+    let ini = dir.join("profiles.ini");
+    debug!("Reading profiles from {:?}", ini);
+    let sections = parse_ini(&fs::read_to_string(&ini)?);
+    let mut install_defaults = sections.iter()
+        .filter(|(name, _)| name.starts_with("Install"))
+        .filter_map(|(_, keys)| keys.get("Default").cloned())
+        .collect::<Vec<_>>();
+    if let Ok(text) = fs::read_to_string(dir.join("installs.ini")) {
+        install_defaults.extend(parse_ini(&text).into_iter().filter_map(|(_, mut keys)| keys.remove("Default")));
+    }
+    let mut profiles = vec![];
+    for (section, keys) in sections.iter().filter(|(name, _)| name.starts_with("Profile")) {
+        let (name, path) = match (keys.get("Name"), keys.get("Path")) {
+            (Some(name), Some(path)) => (name, path),
+            _ => {
+                debug!("Skipping {} in {:?}, which has no name or path", section, ini);
+                continue;
+            }
+        };
+        let profile_dir = if keys.get("IsRelative").is_none_or(|r| r == "1") {
+            dir.join(path)
+        } else {
+            PathBuf::from(path)
+        };
+        let places_db = profile_dir.join("places.sqlite");
+        if !places_db.is_file() {
+            debug!("Skipping profile {:?}, which has no places.sqlite", name);
+            continue;
+        }
+        let is_default = if install_defaults.is_empty() {
+            keys.get("Default").is_some_and(|d| d == "1")
+        } else {
+            install_defaults.contains(path)
+        };
+        profiles.push(Profile {
+            install: install.into(),
+            name: name.clone(),
+            db_size: fs::metadata(&places_db)?.len(),
+            places_db,
+            is_chromium: false,
+            is_default,
+        });
+    }
+    Ok(profiles)
+}
+
+fn get_profiles_in(install: &str, path: &Path, is_chromium: bool) -> Result<Vec<Profile>> {
+    debug!("Using profile path: {:?}", path);
+    let res = fs::read_dir(path)?
+    .map(|entry_result| {
+        let entry = entry_result?;
+        trace!("Considering path {:?}", entry.path());
+        if !entry.path().is_dir() {
+            trace!("  Not dir: {:?}", entry.path());
+            return Ok(None);
+        }
+        let mut path = entry.path().to_owned();
+        let profile_name = path.file_name().unwrap_or_default().to_str().ok_or_else(|| {
+            warn!("  Path has invalid UTF8: {:?}", path);
+            format_err!(ProfileDiscovery, "Path has invalid UTF8: {:?}", path)
+        })?.into();
+        path.push(if is_chromium { "History" } else { "places.sqlite" });
+        if !path.exists() {
+            return Ok(None);
+        }
+        let metadata = fs::metadata(&path)?;
+        let db_size = metadata.len();
+        Ok(Some(Profile {
+            install: install.into(),
+            name: profile_name,
+            places_db: path,
+            db_size,
+            is_chromium,
+            is_default: false,
+        }))
+    }).filter_map(|result: Result<Option<Profile>>| {
+        match result {
+            Ok(val) => val,
+            Err(e) => {
+                debug!("Got error finding profile directory, skipping: {}", e);
+                None
+            }
+        }
+    }).collect::<Vec<_>>();
+    Ok(res)
+}
+
+/// Sorts largest places.sqlite first. Ties are broken by name and then path
+/// (canonicalized, if it can be, so however it was reached), so that
+/// auto-selection picks the same profile every time.
+pub fn sort_profiles(profiles: &mut [Profile]) {
+    profiles.sort_by_cached_key(|p| {
+        let path = fs::canonicalize(&p.places_db).unwrap_or_else(|_| p.places_db.clone());
+        (::std::cmp::Reverse(p.db_size), p.name.clone(), path)
+    });
+}
+
+/// Finds the profile matching `name`, which is either a bare profile name or a
+/// qualified `install/name`. Profiles also match the name of their directory.
+/// Fails if a bare name matches profiles in more than one install.
+pub fn find_profile(profiles: Vec<Profile>, name: &str) -> Result<Profile> {
+    let names = profiles.iter().map(|p| format!("{:?}", p.qualified_name())).collect::<Vec<_>>();
+    let mut matching = profiles.into_iter().filter(|p| {
+        let dir_name = p.places_db.parent().and_then(Path::file_name).and_then(|n| n.to_str());
+        let is_named = |n: &str| p.name == n || dir_name == Some(n);
+        match name.find('/') {
+            Some(i) => p.install == name[..i] && is_named(&name[i + 1..]),
+            None => is_named(name),
+        }
+    }).collect::<Vec<_>>();
+    match matching.len() {
+        0 if names.is_empty() => bail!(ProfileDiscovery, "No profile named {:?} found, as we didn't find any profiles", name),
+        0 => bail!(ProfileDiscovery, "No profile named {:?} found, use one of: {}", name, names.join(", ")),
+        1 => Ok(matching.pop().unwrap()),
+        _ => {
+            let choices = matching.iter()
+                .map(|p| format!("{:?}", p.qualified_name()))
+                .collect::<Vec<_>>();
+            bail!(ProfileDiscovery, "Profile name {:?} is ambiguous, use one of: {}", name, choices.join(", "));
+        }
+    }
+}
+
+/// A summary of the history in a places database.
+#[derive(Clone, Debug)]
+pub struct ProfileStats {
+    pub places: i64,
+    pub hosts: i64,
+    pub first_visit: Option<i64>,
+    pub last_visit: Option<i64>,
+}
+
+impl ProfileStats {
+    pub fn for_db(path: &Path) -> Result<ProfileStats> {
+        let conn = open_source(path)?;
+        let is_chromium = conn.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'keyword_search_terms'",
+            &[], |row| row.get::<_, i64>(0))? != 0;
+        if is_chromium {
+            // Chromium has no rev_host, so count origins (everything up to the
+            // path), and its times are microseconds since 1601.
+            return Ok(conn.query_row("
+                SELECT count(*),
+                       count(DISTINCT substr(url, 1, instr(url, '://') + 2 + instr(substr(url, instr(url, '://') + 3) || '/', '/'))),
+                       min(nullif(last_visit_time, 0)) - 11644473600000000,
+                       max(nullif(last_visit_time, 0)) - 11644473600000000
+                FROM urls
+            ", &[], |row| ProfileStats {
+                places: row.get(0),
+                hosts: row.get(1),
+                first_visit: row.get(2),
+                last_visit: row.get(3),
+            })?);
+        }
+        Ok(conn.query_row("
+            SELECT count(*), count(DISTINCT rev_host), min(last_visit_date), max(last_visit_date)
+            FROM moz_places
+        ", &[], |row| ProfileStats {
+            places: row.get(0),
+            hosts: row.get(1),
+            first_visit: row.get(2),
+            last_visit: row.get(3),
+        })?)
+    }
+}
+
+/// Formats a PRTime (microseconds since the unix epoch) as a YYYY-MM-DD date.
+pub fn format_prtime(prtime: i64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = prtime.div_euclid(86_400_000_000) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str, db_size: u64) -> Profile {
+        Profile {
+            install: "Firefox".into(),
+            name: name.into(),
+            places_db: Path::new("/profiles").join(name).join("places.sqlite"),
+            db_size,
+            is_chromium: false,
+            is_default: false,
+        }
+    }
+
+    #[test]
+    fn sort_profiles_breaks_ties_the_same_way() {
+        let (a, b) = (profile("alpha", 1024), profile("beta", 1024));
+        for order in &[[a.clone(), b.clone()], [b.clone(), a.clone()]] {
+            let mut profiles = order.to_vec();
+            sort_profiles(&mut profiles);
+            assert_eq!(profiles.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["alpha", "beta"]);
+        }
+        // Size still comes first.
+        let mut profiles = vec![a, b, profile("gamma", 2048)];
+        sort_profiles(&mut profiles);
+        assert_eq!(profiles[0].name, "gamma");
+    }
+
+    #[test]
+    fn sort_profiles_compares_canonical_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        for sub in &["a", "z/sub"] {
+            fs::create_dir_all(dir.path().join(sub)).unwrap();
+        }
+        fs::write(dir.path().join("a/places.sqlite"), b"").unwrap();
+        fs::write(dir.path().join("z/places.sqlite"), b"").unwrap();
+        let (mut a, mut z) = (profile("same", 1024), profile("same", 1024));
+        // After `z/places.sqlite` as it's written, but before it once resolved.
+        z.places_db = dir.path().join("z/places.sqlite");
+        a.places_db = dir.path().join("z/sub/../../a/places.sqlite");
+        let mut profiles = vec![z.clone(), a.clone()];
+        sort_profiles(&mut profiles);
+        assert_eq!(profiles.iter().map(|p| &p.places_db).collect::<Vec<_>>(), [&a.places_db, &z.places_db]);
+    }
+}
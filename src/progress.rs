@@ -0,0 +1,132 @@
+use std::cell::{Cell, RefCell};
+use std::io::{self, Write};
+use std::os::raw::{c_int, c_void};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use rusqlite::Connection;
+use ffi;
+
+// How often SQLite calls back while a statement runs, in virtual machine
+// instructions, and how often we redraw at most.
+const PROGRESS_INSTRUCTIONS: c_int = 100_000;
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const SPINNER: &[char] = &['|', '/', '-', '\\'];
+
+// A status line on stderr saying which phase we're in and for how long, so
+// that anonymizing a big database doesn't look hung. Does nothing unless
+// enabled. Also interrupts the statements it watches once `cancel` is set.
+pub(crate) struct Progress {
+    enabled: bool,
+    cancel: Option<Arc<AtomicBool>>,
+    phase: RefCell<String>,
+    detail: RefCell<String>,
+    phase_started: Cell<Instant>,
+    last_drawn: Cell<Instant>,
+    ticks: Cell<usize>,
+}
+
+impl Progress {
+    pub(crate) fn new(enabled: bool, cancel: Option<Arc<AtomicBool>>) -> Progress {
+        Progress {
+            enabled,
+            cancel,
+            phase: RefCell::new(String::new()),
+            detail: RefCell::new(String::new()),
+            phase_started: Cell::new(Instant::now()),
+            last_drawn: Cell::new(Instant::now()),
+            ticks: Cell::new(0),
+        }
+    }
+
+    // Starts a new phase, like copying the input or anonymizing a table.
+    pub(crate) fn phase(&self, phase: String) {
+        if !self.enabled {
+            return;
+        }
+        *self.phase.borrow_mut() = phase;
+        self.detail.borrow_mut().clear();
+        self.phase_started.set(Instant::now());
+        self.draw();
+    }
+
+    // Says how far into the current phase we are, like how many rows are
+    // done, shown after it on the status line.
+    pub(crate) fn detail(&self, detail: String) {
+        if !self.enabled {
+            return;
+        }
+        *self.detail.borrow_mut() = detail;
+        self.tick();
+    }
+
+    // Redraws the status line, if it's been a while, to show we're still at it.
+    pub(crate) fn tick(&self) {
+        if self.enabled && self.last_drawn.get().elapsed() >= REDRAW_INTERVAL {
+            self.ticks.set(self.ticks.get().wrapping_add(1));
+            self.draw();
+        }
+    }
+
+    fn draw(&self) {
+        let spinner = SPINNER[self.ticks.get() % SPINNER.len()];
+        eprint!("\r\x1b[K{} {} {:.0}s", spinner, self.phase.borrow(), self.phase_started.get().elapsed().as_secs_f64());
+        if !self.detail.borrow().is_empty() {
+            eprint!(", {}", self.detail.borrow());
+        }
+        let _ = io::stderr().flush();
+        self.last_drawn.set(Instant::now());
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst))
+    }
+
+    // Whether we need to hear from SQLite while statements run.
+    fn watching(&self) -> bool {
+        self.enabled || self.cancel.is_some()
+    }
+
+    // Ticks while statements run on `conn`, and interrupts them if cancelled,
+    // until the returned guard is dropped.
+    pub(crate) fn watch<'a>(&'a self, conn: &'a Connection) -> ProgressHandler<'a> {
+        if self.watching() {
+            unsafe {
+                ffi::sqlite3_progress_handler(conn.handle(), PROGRESS_INSTRUCTIONS, Some(on_progress),
+                                              self as *const Progress as *mut c_void);
+            }
+        }
+        ProgressHandler { progress: self, conn }
+    }
+}
+
+// Clears the status line once we're done (or have failed).
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.enabled {
+            eprint!("\r\x1b[K");
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+pub(crate) struct ProgressHandler<'a> {
+    progress: &'a Progress,
+    conn: &'a Connection,
+}
+
+impl<'a> Drop for ProgressHandler<'a> {
+    fn drop(&mut self) {
+        if self.progress.watching() {
+            unsafe { ffi::sqlite3_progress_handler(self.conn.handle(), 0, None, ::std::ptr::null_mut()) };
+        }
+    }
+}
+
+unsafe extern "C" fn on_progress(progress: *mut c_void) -> c_int {
+    let progress = &*(progress as *const Progress);
+    progress.tick();
+    // Anything but 0 interrupts the statement.
+    progress.cancelled() as c_int
+}
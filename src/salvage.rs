@@ -0,0 +1,203 @@
+use std::path::Path;
+
+use rusqlite::{Connection, OpenFlags};
+use rusqlite::types::{ToSql, Value};
+use tempfile;
+
+use Result;
+
+// How far we've given up looking past damage in a table for more rows we can
+// read. We double how far we skip ahead each time we land in it again.
+const MAX_SKIP: i64 = 1 << 48;
+
+// Copies what can still be read of the corrupt database at `path` into a new
+// one: the schema, then each table's rows one at a time in rowid order,
+// skipping past the rows SQLite can't read (which go a damaged page at a
+// time), and then the indexes and triggers, which are rebuilt from what was
+// recovered. Anything that won't go in is logged and left out.
+pub(crate) fn salvage(path: &Path) -> Result<tempfile::NamedTempFile> {
+    let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let temp = tempfile::NamedTempFile::new()?;
+    let target = Connection::open(temp.path())?;
+    // It's only read by us, and thrown away at the end.
+    target.execute_batch("
+        PRAGMA journal_mode = OFF;
+        PRAGMA synchronous = OFF;
+    ")?;
+    let version = source.query_row("PRAGMA user_version", &[], |row| row.get::<_, i64>(0))?;
+    target.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+
+    let schema = (|| -> ::rusqlite::Result<Vec<(String, String, String)>> {
+        let mut stmt = source.prepare("
+            SELECT type, name, sql FROM sqlite_master
+            WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+            ORDER BY rowid
+        ")?;
+        let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1), row.get(2)))?;
+        rows.collect()
+    })().map_err(|e| format_err!(Corrupt, "Can't read the schema of {:?}, so there's nothing to salvage: {}", path, e))?;
+
+    let (mut copied, mut lost) = (0, 0);
+    for (kind, name, sql) in schema.iter().filter(|(kind, _, _)| kind == "table") {
+        if let Err(e) = target.execute_batch(sql) {
+            warn!("Can't recreate {} {}, leaving it out: {}", kind, name, e);
+            continue;
+        }
+        let (rows, damaged) = copy_rows(&source, &target, name)?;
+        copied += rows;
+        if damaged > 0 {
+            warn!("Salvaged {} rows of {}, skipping {} damaged stretch(es) of it", rows, name, damaged);
+            lost += 1;
+        }
+    }
+    // Indexes and triggers last, so they're built from the rows we got, and
+    // don't fire as we copy them.
+    for (kind, name, sql) in schema.iter().filter(|(kind, _, _)| kind != "table") {
+        if let Err(e) = target.execute_batch(sql) {
+            warn!("Can't recreate {} {}, leaving it out: {}", kind, name, e);
+        }
+    }
+    info!("Salvaged {} rows from {:?}, with {} table(s) missing some", copied, path, lost);
+    Ok(temp)
+}
+
+// Copies the rows of `table` we can read from `source` to `target`, with
+// their rowids. Returns how many it copied, and how many times it had to skip
+// over damage to carry on.
+fn copy_rows(source: &Connection, target: &Connection, table: &str) -> Result<(u64, u64)> {
+    let cols = match source.prepare(&format!("SELECT * FROM \"{}\"", table)) {
+        Ok(stmt) => stmt.column_names().iter().map(|col| format!("\"{}\"", col)).collect::<Vec<_>>(),
+        Err(e) => {
+            warn!("Can't read {}, leaving it empty: {}", table, e);
+            return Ok((0, 1));
+        }
+    };
+    // Tables without rowids can't be read past damage, so we take what we
+    // can get of them in one go.
+    let has_rowid = source.prepare(&format!("SELECT rowid FROM \"{}\" LIMIT 0", table)).is_ok();
+    let (select, insert) = if has_rowid {
+        (format!("SELECT rowid, * FROM \"{}\" WHERE rowid > ?1 ORDER BY rowid", table),
+         format!("INSERT INTO \"{}\" (rowid, {}) VALUES ({})", table, cols.join(", "),
+                 vec!["?"; cols.len() + 1].join(", ")))
+    } else {
+        (format!("SELECT NULL, * FROM \"{}\" WHERE ?1 IS NOT NULL", table),
+         format!("INSERT INTO \"{}\" ({}) VALUES ({})", table, cols.join(", "), vec!["?"; cols.len()].join(", ")))
+    };
+    let mut insert = target.prepare(&insert)?;
+    target.execute_batch("BEGIN")?;
+    // Start just before the first row if we can read where that is, since
+    // skipping ahead from i64::MIN would never get as far as the rowids in use.
+    let first = if has_rowid {
+        source.query_row(&format!("SELECT min(rowid) FROM \"{}\"", table), &[], |row| row.get_checked(0))
+            .ok().and_then(|first| first.ok()).and_then(|first: Option<i64>| first)
+    } else {
+        None
+    };
+    let mut after = first.map_or(i64::MIN, |first| first.saturating_sub(1));
+    let (mut skip, mut copied, mut damaged) = (1, 0, 0);
+    loop {
+        match copy_from(source, &select, after, &mut insert, has_rowid, &mut copied) {
+            Ok(None) => break,
+            Ok(Some(last)) => {
+                after = last;
+                skip = 1;
+            }
+            Err(e) => {
+                debug!("Hit damage in {} after rowid {}: {}", table, after, e);
+                // Rowids are hardly ever negative, so if the damage is before
+                // the positive ones, carry on from those.
+                if after < 0 {
+                    after = -1;
+                }
+            }
+        }
+        // A new stretch of damage, rather than more of the last one.
+        if skip == 1 {
+            damaged += 1;
+        }
+        if !has_rowid || skip > MAX_SKIP {
+            break;
+        }
+        after = after.saturating_add(skip);
+        skip *= 2;
+    }
+    target.execute_batch("COMMIT")?;
+    Ok((copied, damaged))
+}
+
+// Copies rows of a table from just after the rowid `after`, until it runs out
+// (returning None) or hits a row it can't read (returning the last rowid it
+// could, or an error if it couldn't read any).
+fn copy_from(source: &Connection, select: &str, after: i64, insert: &mut ::rusqlite::Statement,
+             has_rowid: bool, copied: &mut u64) -> Result<Option<i64>> {
+    let mut stmt = source.prepare(select)?;
+    let mut rows = stmt.query(&[&after])?;
+    let mut last = None;
+    while let Some(row) = rows.next() {
+        let values = match row.and_then(|row| (0..row.column_count()).map(|i| row.get_checked::<_, Value>(i))
+                                                .collect::<::rusqlite::Result<Vec<_>>>()) {
+            Ok(values) => values,
+            Err(e) => match last {
+                Some(last) => {
+                    debug!("Can't read the row after rowid {}: {}", last, e);
+                    return Ok(Some(last));
+                }
+                None => return Err(e.into()),
+            },
+        };
+        let params = values.iter().skip(if has_rowid { 0 } else { 1 }).map(|v| v as &dyn ToSql).collect::<Vec<_>>();
+        match insert.execute(&params) {
+            Ok(_) => *copied += 1,
+            Err(e) => debug!("Can't salvage a row: {}", e),
+        }
+        last = match values[0] {
+            Value::Integer(rowid) => Some(rowid),
+            // Without rowids, we only need to know we got somewhere.
+            _ => Some(after),
+        };
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use super::*;
+
+    #[test]
+    fn salvages_past_a_damaged_first_page() {
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        {
+            let conn = Connection::open(temp.path()).unwrap();
+            conn.execute_batch("
+                PRAGMA page_size = 1024;
+                CREATE TABLE t(v TEXT);
+                WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500)
+                INSERT INTO t(rowid, v) SELECT i, printf('row%06d', i) || zeroblob(100) FROM n;
+            ").unwrap();
+        }
+        // Zero the page holding the first row, so SQLite can't read where the
+        // table starts.
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(temp.path()).unwrap();
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        let page = data.chunks(1024).position(|page| page.windows(9).any(|w| w == b"row000001")).unwrap();
+        assert!(page > 0);
+        file.seek(SeekFrom::Start(page as u64 * 1024)).unwrap();
+        file.write_all(&[0; 1024]).unwrap();
+        drop(file);
+
+        let salvaged = salvage(temp.path()).unwrap();
+        let conn = Connection::open(salvaged.path()).unwrap();
+        let (count, first, last) = conn.query_row("SELECT count(*), min(rowid), max(rowid) FROM t", &[], |row| {
+            (row.get::<_, i64>(0), row.get::<_, i64>(1), row.get::<_, i64>(2))
+        }).unwrap();
+        assert!(first > 1);
+        assert_eq!(last, 500);
+        assert_eq!(count, 501 - first);
+        let v: String = conn.query_row("SELECT substr(v, 1, 9) FROM t WHERE rowid = 500", &[], |row| row.get(0)).unwrap();
+        assert_eq!(v, "row000500");
+    }
+}
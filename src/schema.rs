@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+
+use Result;
+use policy::{Policies, Policy};
+
+/// The places schema version, which Firefox stores in `PRAGMA user_version`.
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row("PRAGMA user_version", &[], |row| row.get(0))?)
+}
+
+// The oldest places schema version we handle (Firefox 4). Anything older
+// predates most of the tables and columns we know about.
+pub(crate) const OLDEST_KNOWN_SCHEMA: i64 = 11;
+
+// The newest places schema version we know about. Newer ones may store things
+// in ways we don't know to anonymize.
+pub(crate) const NEWEST_KNOWN_SCHEMA: i64 = 78;
+
+// What a column holds, going by its name and declared type. This decides how
+// it gets anonymized, so that tables and columns we've never seen (from newer
+// schemas, extensions, or other Mozilla databases) still get sensible handling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ColumnClass {
+    Url,
+    Host,
+    // A host reversed, with a trailing dot, like moz_places.rev_host.
+    RevHost,
+    // The scheme and anything before the host, like moz_origins.prefix.
+    Prefix,
+    Json,
+    // A page or bookmark title.
+    Title,
+    // An origin, like moz_perms.origin, or just its attributes, like
+    // moz_cookies.originAttributes.
+    Origin,
+    // An origin as localStorage keys it, like `moc.elpmaxe.:https:443`.
+    OriginKey,
+    // A url or file path, without a scheme or host, like moz_cookies.path.
+    Path,
+    Guid,
+    Timestamp,
+    Numeric,
+    // Anything else, which we treat as free text.
+    Text,
+}
+
+impl ColumnClass {
+    pub(crate) fn classify(name: &str, decl_type: &str) -> ColumnClass {
+        let name = name.to_lowercase();
+        let decl_type = decl_type.to_uppercase();
+        // SQLite's own type affinity rules, minus TEXT and BLOB.
+        let numeric = ["INT", "REAL", "FLOA", "DOUB", "NUM", "BOOL"].iter()
+            .any(|t| decl_type.contains(t));
+        if name == "rev_host" {
+            ColumnClass::RevHost
+        } else if name == "host" || name.ends_with("_host") {
+            ColumnClass::Host
+        } else if name == "prefix" {
+            ColumnClass::Prefix
+        } else if name.ends_with("_json") || decl_type == "JSON" || name == "unknown_fields" {
+            ColumnClass::Json
+        } else if name == "title" || name.ends_with("_title") {
+            ColumnClass::Title
+        } else if !numeric && (name == "origin" || name.ends_with("_origin") || name == "originattributes") {
+            ColumnClass::Origin
+        } else if !numeric && (name == "path" || name.ends_with("_path")) {
+            ColumnClass::Path
+        } else if name == "guid" || name.ends_with("_guid") {
+            ColumnClass::Guid
+        } else if numeric && ["date", "_at", "added", "modified", "removed"].iter()
+                .any(|s| name.ends_with(s)) {
+            ColumnClass::Timestamp
+        } else if numeric {
+            ColumnClass::Numeric
+        } else if ["url", "uri", "spec"].iter().any(|s| name == *s || name.ends_with(&format!("_{}", s))) {
+            ColumnClass::Url
+        } else {
+            ColumnClass::Text
+        }
+    }
+}
+
+// Columns whose names don't say what they hold.
+const CLASS_OVERRIDES: &[(&str, &str, ColumnClass)] = &[
+    // The site each of content-prefs.sqlite's prefs is for.
+    ("groups", "name", ColumnClass::Host),
+    ("webappsstore2", "originKey", ColumnClass::OriginKey),
+    // The same, in schemas before Firefox 47.
+    ("webappsstore2", "scope", ColumnClass::OriginKey),
+    // Chrome's download referrers, and the urls its most visited sites are
+    // grouped by.
+    ("downloads", "referrer", ColumnClass::Url),
+    ("segments", "name", ColumnClass::Url),
+    // Safari's hosts without their `www.` or public suffix, like `google`.
+    ("history_items", "domain_expansion", ColumnClass::Host),
+    // Firefox for Android's synced bookmarks refer to their parents by GUID.
+    ("moz_bookmarks_synced", "parentGuid", ColumnClass::Guid),
+    ("moz_bookmarks_synced_structure", "parentGuid", ColumnClass::Guid),
+];
+
+#[derive(Debug, Clone)]
+pub(crate) struct TableInfo {
+    pub(crate) name: String,
+    pub(crate) cols: Vec<String>,
+    pub(crate) classes: Vec<ColumnClass>,
+    // Columns with a UNIQUE index (or constraint) of their own.
+    pub(crate) unique: Vec<String>,
+    // Columns in any UNIQUE index, including ones over several columns.
+    pub(crate) in_unique_index: Vec<String>,
+}
+
+impl TableInfo {
+    pub(crate) fn for_table(name: String, conn: &Connection, generic: bool) -> Result<TableInfo> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", name))?;
+        let mut rows = stmt.query(&[])?;
+        let (mut cols, mut classes) = (vec![], vec![]);
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let col: String = row.get("name");
+            let decl_type: String = row.get("type");
+            let class = CLASS_OVERRIDES.iter()
+                .find(|&&(table, c, _)| !generic && table == name && c == col)
+                .map_or_else(|| ColumnClass::classify(&col, &decl_type), |&(_, _, class)| class);
+            debug!("{}.{} ({}) looks like {:?}", name, col, decl_type, class);
+            cols.push(col);
+            classes.push(class);
+        }
+        let (mut unique, mut in_unique_index) = (vec![], vec![]);
+        let mut stmt = conn.prepare(&format!("PRAGMA index_list({})", name))?;
+        let indexes = stmt.query_map(&[], |row| (row.get::<_, String>("name"), row.get::<_, bool>("unique")))?
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+        for (index, is_unique) in indexes {
+            if !is_unique {
+                continue;
+            }
+            let mut stmt = conn.prepare(&format!("PRAGMA index_info({})", index))?;
+            let index_cols = stmt.query_map(&[], |row| row.get::<_, Option<String>>("name"))?
+                .collect::<::std::result::Result<Vec<_>, _>>()?;
+            if let [Some(ref col)] = index_cols[..] {
+                unique.push(col.clone());
+            }
+            in_unique_index.extend(index_cols.into_iter().flatten());
+        }
+        Ok(TableInfo { name, cols, classes, unique, in_unique_index })
+    }
+
+    // Columns of the given class.
+    pub(crate) fn cols_of(&self, class: ColumnClass) -> impl Iterator<Item = &str> {
+        self.cols.iter().zip(&self.classes)
+            .filter(move |&(_, &c)| c == class)
+            .map(|(col, _)| col.as_str())
+    }
+
+    // `column_expr` gives the SQL that replaces each column, or None to leave
+    // it as is. None if there's nothing to replace. If `chunked`, it only
+    // updates the rows with rowids in (?1, ?2].
+    pub(crate) fn make_update<F>(&self, column_expr: F, exclude: Option<&str>, chunked: bool) -> Option<String>
+        where F: Fn(&str, ColumnClass) -> Option<String>
+    {
+        let sets = self.cols.iter().zip(&self.classes)
+            .filter_map(|(col, &class)| column_expr(col, class).map(|expr| format!("{} = {}", col, expr)))
+            .collect::<Vec<_>>();
+        if sets.is_empty() {
+            return None;
+        }
+        let sets = sets.join(",\n    ");
+        let mut conds = vec![];
+        if chunked {
+            conds.push("rowid > ?1 AND rowid <= ?2".to_owned());
+        }
+        if let Some(cond) = exclude {
+            conds.push(format!("NOT ({})", cond));
+        }
+        Some(if conds.is_empty() {
+            format!("UPDATE {}\nSET {}", self.name, sets)
+        } else {
+            format!("UPDATE {}\nSET {}\nWHERE {}", self.name, sets, conds.join(" AND "))
+        })
+    }
+}
+
+// Reads every table's columns, classified by their names, and unless
+// `generic`, the overrides for the tables of databases we know.
+pub(crate) fn read_tables(conn: &Connection, generic: bool) -> Result<Vec<TableInfo>> {
+    let mut stmt = conn.prepare("
+        SELECT name FROM sqlite_master
+        WHERE type = 'table'
+          AND name NOT LIKE 'sqlite_%' -- ('sqlite_sequence', 'sqlite_stat1', 'sqlite_master', anyt)
+    ")?;
+    let mut rows = stmt.query(&[])?;
+    let mut tables = vec![];
+    while let Some(row_or_error) = rows.next() {
+        tables.push(TableInfo::for_table(row_or_error?.get("name"), conn, generic)?);
+    }
+    Ok(tables)
+}
+
+// Tables that Firefox itself creates in places.sqlite (across schema versions).
+// Anything else was most likely added by an extension, and we know nothing
+// about what it contains.
+pub(crate) const KNOWN_TABLES: &[&str] = &[
+    "moz_anno_attributes",
+    "moz_annos",
+    "moz_bookmarks",
+    "moz_bookmarks_deleted",
+    "moz_bookmarks_roots",
+    "moz_favicons",
+    "moz_historyvisits",
+    "moz_historyvisits_extra",
+    "moz_hosts",
+    "moz_inputhistory",
+    "moz_items_annos",
+    "moz_keywords",
+    "moz_meta",
+    "moz_origins",
+    "moz_places",
+    "moz_places_extra",
+    "moz_places_metadata",
+    "moz_places_metadata_groups_to_snapshots",
+    "moz_places_metadata_search_queries",
+    "moz_places_metadata_snapshots",
+    "moz_places_metadata_snapshots_extra",
+    "moz_places_metadata_snapshots_groups",
+    "moz_previews_tombstones",
+    "moz_session_metadata",
+    "moz_session_to_places",
+];
+
+// Tables that only Firefox for Android's places database (the one from
+// application-services) has, on top of its own versions of Firefox's.
+pub(crate) const FENIX_TABLES: &[&str] = &[
+    "moz_bookmarks_synced",
+    "moz_bookmarks_synced_structure",
+    "moz_bookmarks_synced_tag_relation",
+    "moz_historyvisit_tombstones",
+    "moz_places_stale_frecencies",
+    "moz_places_tombstones",
+    "moz_tags",
+];
+
+// Databases other than places.sqlite that we know how to anonymize, and a
+// table that tells each apart.
+pub(crate) const OTHER_DATABASES: &[(&str, &str)] = &[
+    ("favicons.sqlite", "moz_icons"),
+    ("formhistory.sqlite", "moz_formhistory"),
+    ("cookies.sqlite", "moz_cookies"),
+    ("permissions.sqlite", "moz_perms"),
+    ("content-prefs.sqlite", "prefs"),
+    ("webappsstore.sqlite", "webappsstore2"),
+    ("Chrome's History", "keyword_search_terms"),
+    ("Safari's History.db", "history_items"),
+];
+
+// Tables of the other databases, which we know how to anonymize too.
+pub(crate) const OTHER_KNOWN_TABLES: &[&str] = &[
+    // favicons.sqlite
+    "moz_icons",
+    "moz_icons_to_pages",
+    "moz_pages_w_icons",
+    // formhistory.sqlite
+    "moz_deleted_formhistory",
+    "moz_formhistory",
+    "moz_history_to_sources",
+    "moz_sources",
+    // cookies.sqlite
+    "moz_cookies",
+    // permissions.sqlite (moz_hosts is also its table from before Firefox 42)
+    "moz_perms",
+    // content-prefs.sqlite
+    "groups",
+    "prefs",
+    "settings",
+    // webappsstore.sqlite
+    "webappsstore2",
+    // Chrome's (and Chromium's) History
+    "cluster_keywords",
+    "cluster_visit_duplicates",
+    "clusters",
+    "clusters_and_visits",
+    "content_annotations",
+    "context_annotations",
+    "downloads",
+    "downloads_reroute_info",
+    "downloads_slices",
+    "downloads_url_chains",
+    "history_sync_metadata",
+    "keyword_search_terms",
+    "meta",
+    "segment_usage",
+    "segments",
+    "typed_url_sync_metadata",
+    "urls",
+    "visit_source",
+    "visited_links",
+    "visits",
+    // Safari's History.db
+    "history_client_versions",
+    "history_event_listeners",
+    "history_events",
+    "history_items",
+    "history_items_to_tags",
+    "history_tags",
+    "history_tombstones",
+    "history_visits",
+    "metadata",
+];
+
+// Rows that get special handling, and so should be skipped by the generic
+// per-table anonymization.
+#[derive(Default, Clone, Debug)]
+pub(crate) struct Exclusions {
+    conditions: HashMap<String, Vec<String>>,
+}
+
+impl Exclusions {
+    pub(crate) fn add(&mut self, table: &str, condition: String) {
+        self.conditions.entry(table.into()).or_default().push(condition);
+    }
+
+    // An SQL expression that's true for the excluded rows of `table`, if any.
+    pub(crate) fn condition(&self, table: &str) -> Option<String> {
+        self.conditions.get(table).map(|conds| {
+            conds.iter().map(|c| format!("({})", c)).collect::<Vec<_>>().join(" OR ")
+        })
+    }
+}
+
+// Guids of the bookmark roots that every places database has.
+pub(crate) const BOOKMARK_ROOT_GUIDS: &str = "(
+    'root________', 'menu________', 'toolbar_____', 'tags________', 'unfiled_____', 'mobile______'
+)";
+
+// Annotations Firefox uses to store download history.
+pub(crate) const DOWNLOAD_ANNOS: &str = "('downloads/destinationFileURI', 'downloads/metaData')";
+
+// Content prefs that hold file paths (the last directory a file was uploaded
+// from or downloaded to on the site), which are anonymized as paths rather
+// than kept like the rest.
+pub(crate) const PATH_CONTENT_PREFS: &str = "('browser.upload.lastDir', 'browser.download.lastDir')";
+
+// Columns we recompute or overwrite rather than anonymize, so they're allowed
+// to differ from the source even though they aren't text.
+pub(crate) const CLEARED_COLUMNS: &[(&str, &str)] = &[
+    ("moz_places", "url_hash"),
+    ("moz_pages_w_icons", "page_url_hash"),
+    ("moz_icons", "fixed_icon_url_hash"),
+];
+
+// Cookie values, which are wiped or hashed rather than anonymized, as they're
+// mostly session tokens and the like that mean nothing once anonymized.
+pub(crate) const COOKIE_VALUES: (&str, &str) = ("moz_cookies", "value");
+
+// localStorage values, which are replaced with placeholders of the same
+// length, so quotas and the like still behave the same. They're anything a
+// site likes to store, so anonymizing them piece by piece wouldn't be enough.
+pub(crate) const LOCAL_STORAGE_VALUES: (&str, &str) = ("webappsstore2", "value");
+
+// The SHA-256 hashes of Chrome's downloads, which would identify the files,
+// and are emptied like Chrome does when it doesn't know them.
+pub(crate) const DOWNLOAD_HASHES: (&str, &str) = ("downloads", "hash");
+
+// Hashes Firefox looks urls up by, with the url column each is the hash of,
+// and whether it's hashed after `fixup_url`. They're recomputed from the
+// anonymized urls.
+pub(crate) const URL_HASH_COLUMNS: &[(&str, &str, &str, bool)] = &[
+    ("moz_places", "url_hash", "url", false),
+    ("moz_pages_w_icons", "page_url_hash", "page_url", false),
+    ("moz_icons", "fixed_icon_url_hash", "icon_url", true),
+];
+
+// Columns that refer to rows of another table (by `table.column`), which
+// should still resolve after anonymizing. Nothing declares them as foreign
+// keys, so they're listed here.
+pub(crate) const REFERENCES: &[(&str, &str, &str, &str)] = &[
+    ("moz_places", "origin_id", "moz_origins", "id"),
+    ("moz_historyvisits", "place_id", "moz_places", "id"),
+    ("moz_historyvisits", "from_visit", "moz_historyvisits", "id"),
+    ("moz_bookmarks", "fk", "moz_places", "id"),
+    ("moz_bookmarks", "parent", "moz_bookmarks", "id"),
+    ("moz_bookmarks", "keyword_id", "moz_keywords", "id"),
+    ("moz_keywords", "place_id", "moz_places", "id"),
+    ("moz_inputhistory", "place_id", "moz_places", "id"),
+    ("moz_annos", "place_id", "moz_places", "id"),
+    ("moz_annos", "anno_attribute_id", "moz_anno_attributes", "id"),
+    ("moz_items_annos", "item_id", "moz_bookmarks", "id"),
+    ("moz_items_annos", "anno_attribute_id", "moz_anno_attributes", "id"),
+    ("moz_places_metadata", "place_id", "moz_places", "id"),
+    ("moz_places_metadata", "referrer_place_id", "moz_places", "id"),
+    ("moz_icons_to_pages", "page_id", "moz_pages_w_icons", "id"),
+    ("moz_icons_to_pages", "icon_id", "moz_icons", "id"),
+    ("visits", "url", "urls", "id"),
+    ("keyword_search_terms", "url_id", "urls", "id"),
+    ("segments", "url_id", "urls", "id"),
+    ("segment_usage", "segment_id", "segments", "id"),
+    ("history_visits", "history_item", "history_items", "id"),
+];
+
+// Columns whose values (together) should be unique, whether or not the schema
+// says so, and which replacements that collided would duplicate.
+pub(crate) const UNIQUE_KEYS: &[(&str, &[&str])] = &[
+    ("moz_places", &["url"]),
+    ("moz_places", &["guid"]),
+    ("moz_origins", &["prefix", "host"]),
+    ("moz_bookmarks", &["guid"]),
+    ("moz_keywords", &["keyword"]),
+    ("moz_anno_attributes", &["name"]),
+    ("moz_inputhistory", &["place_id", "input"]),
+    ("moz_pages_w_icons", &["page_url"]),
+    ("urls", &["url"]),
+    ("history_items", &["url"]),
+];
+
+// Counters that scrub level 3 and up reduce.
+pub(crate) const COUNT_COLUMNS: &[(&str, &str)] = &[
+    ("moz_places", "visit_count"),
+    ("moz_places", "frecency"),
+    ("moz_places", "typed"),
+    ("moz_origins", "frecency"),
+    ("moz_inputhistory", "use_count"),
+    // How long and how actively each page was used.
+    ("moz_places_metadata", "total_view_time"),
+    ("moz_places_metadata", "typing_time"),
+    ("moz_places_metadata", "key_presses"),
+    ("moz_places_metadata", "scrolling_time"),
+    ("moz_places_metadata", "scrolling_distance"),
+    ("moz_places", "visit_count_local"),
+    ("moz_places", "visit_count_remote"),
+    ("moz_formhistory", "timesUsed"),
+    ("urls", "visit_count"),
+    ("urls", "typed_count"),
+    ("segment_usage", "visit_count"),
+    ("history_items", "visit_count"),
+    ("history_items", "visit_count_score"),
+];
+
+// PRTime timestamps that scrub level 4 and up reduce.
+pub(crate) const DATE_COLUMNS: &[(&str, &str)] = &[
+    ("moz_places", "last_visit_date"),
+    ("moz_historyvisits", "visit_date"),
+    ("moz_bookmarks", "dateAdded"),
+    ("moz_bookmarks", "lastModified"),
+    ("moz_bookmarks_deleted", "dateRemoved"),
+    ("moz_annos", "dateAdded"),
+    ("moz_annos", "lastModified"),
+    ("moz_items_annos", "dateAdded"),
+    ("moz_items_annos", "lastModified"),
+    ("moz_formhistory", "firstUsed"),
+    ("moz_formhistory", "lastUsed"),
+    ("moz_deleted_formhistory", "timeDeleted"),
+    ("moz_cookies", "creationTime"),
+    ("moz_cookies", "lastAccessed"),
+    // Chrome's timestamps are in microseconds too, but since 1601.
+    ("urls", "last_visit_time"),
+    ("visits", "visit_time"),
+    ("downloads", "start_time"),
+    ("downloads", "end_time"),
+    ("downloads", "last_access_time"),
+    ("segment_usage", "time_slot"),
+];
+
+// Firefox for Android's timestamps, which are all in milliseconds, so are used
+// in place of DATE_COLUMNS and MS_DATE_COLUMNS for its database.
+pub(crate) const FENIX_MS_DATE_COLUMNS: &[(&str, &str)] = &[
+    ("moz_places", "last_visit_date_local"),
+    ("moz_places", "last_visit_date_remote"),
+    ("moz_historyvisits", "visit_date"),
+    ("moz_historyvisit_tombstones", "visit_date"),
+    ("moz_bookmarks", "dateAdded"),
+    ("moz_bookmarks", "lastModified"),
+    ("moz_bookmarks_deleted", "dateRemoved"),
+    ("moz_bookmarks_synced", "dateAdded"),
+    ("moz_bookmarks_synced", "serverModified"),
+    ("moz_tags", "lastModified"),
+    ("moz_places_metadata", "created_at"),
+    ("moz_places_metadata", "updated_at"),
+];
+
+// Like DATE_COLUMNS, but in milliseconds rather than PRTime's microseconds.
+pub(crate) const MS_DATE_COLUMNS: &[(&str, &str)] = &[
+    ("moz_places_metadata", "created_at"),
+    ("moz_places_metadata", "updated_at"),
+    ("moz_places_metadata_snapshots", "created_at"),
+    ("moz_places_metadata_snapshots", "removed_at"),
+    ("moz_places_metadata_snapshots", "first_interaction_at"),
+    ("moz_places_metadata_snapshots", "last_interaction_at"),
+    ("moz_session_metadata", "last_saved_at"),
+    ("moz_perms", "modificationTime"),
+    ("moz_hosts", "modificationTime"),
+];
+
+// Like DATE_COLUMNS, but in (possibly fractional) seconds. Safari's are
+// since 2001, which started at midnight like 1970 did.
+pub(crate) const SECONDS_DATE_COLUMNS: &[(&str, &str)] = &[
+    ("history_visits", "visit_time"),
+    ("history_tombstones", "start_time"),
+    ("history_tombstones", "end_time"),
+    ("history_tags", "modification_timestamp"),
+    ("history_event_listeners", "last_seen"),
+    ("history_events", "event_time"),
+];
+
+// Rewrites each of `columns` that exists in `tables` to `expr(column)`, apart
+// from those the policies say to keep or clear, running the SQL with `execute`.
+pub(crate) fn scrub_columns<E, F>(execute: E, tables: &[TableInfo], policies: &Policies,
+                                  columns: &[(&str, &str)], expr: F)
+    -> Result<()> where E: Fn(&str) -> Result<i32>, F: Fn(&str) -> String
+{
+    for &(table, col) in columns {
+        match policies.for_column(table, col) {
+            Some(Policy::Keep) | Some(Policy::Null) | Some(Policy::Hash) => {
+                debug!("Policy overrides scrubbing {}.{}, skipping", table, col);
+                continue;
+            }
+            _ => {}
+        }
+        let present = tables.iter().any(|info| info.name == table && info.cols.iter().any(|c| c == col));
+        if !present {
+            debug!("No {}.{} in this schema, skipping", table, col);
+            continue;
+        }
+        execute(&format!("UPDATE {} SET {} = {}", table, col, expr(col)))?;
+    }
+    Ok(())
+}
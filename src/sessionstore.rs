@@ -0,0 +1,190 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use serde_json::{self, Value};
+use tempfile;
+
+use decompress::{MOZLZ4_MAGIC, mozlz4_decode, mozlz4_encode};
+use schema::ColumnClass;
+use Result;
+
+// Keys of the session store whose values are urls, wherever they are (tabs'
+// history entries, closed tabs and windows, and their children).
+const URL_KEYS: &[&str] = &["url", "originalURI", "resultPrincipalURI", "image", "userTypedValue"];
+
+const TITLE_KEYS: &[&str] = &["title"];
+
+// Keys that are removed along with everything under them: form contents,
+// session storage and cookies, anything extensions stored, the HTML of srcdoc
+// iframes, and serialized principals, CSPs and referrers, which embed urls in
+// ways we can't anonymize in place. Firefox restores tabs without them.
+const REMOVED_KEYS: &[&str] = &[
+    "formdata",
+    "storage",
+    "cookies",
+    "extData",
+    "structuredCloneState",
+    "srcdocData",
+    "triggeringPrincipal",
+    "triggeringPrincipal_base64",
+    "principalToInherit",
+    "principalToInherit_base64",
+    "partitionedPrincipalToInherit_base64",
+    "iconLoadingPrincipal",
+    "storagePrincipal",
+    "csp",
+    "policyContainer",
+    "referrer",
+    "referrerInfo",
+];
+
+/// A session store (like `sessionstore.jsonlz4`, or one of the files in
+/// `sessionstore-backups`), and whether it was mozlz4 compressed.
+pub(crate) struct SessionStore {
+    pub(crate) state: Value,
+    pub(crate) compressed: bool,
+}
+
+impl SessionStore {
+    // Reads `path` if it's a session store, mozlz4 compressed or not, and
+    // returns None if it's something else (like a database).
+    pub(crate) fn read(path: &Path) -> Result<Option<SessionStore>> {
+        let file = fs::read(path)?;
+        let (data, compressed) = if file.starts_with(MOZLZ4_MAGIC) {
+            (mozlz4_decode(&file[MOZLZ4_MAGIC.len()..], path)?, true)
+        } else {
+            (file, false)
+        };
+        if data.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'{') {
+            return Ok(None);
+        }
+        let state = serde_json::from_slice(&data)
+            .map_err(|e| format_err!(BadFile, "{:?} looks like a session store, but isn't valid JSON: {}", path, e))?;
+        Ok(Some(SessionStore { state, compressed }))
+    }
+
+    // Writes the session store to `path`, compressed if it was.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(&self.state).map_err(|e| format_err!(Other, "Can't encode the session store: {}", e))?;
+        let data = if self.compressed { mozlz4_encode(&data) } else { data };
+        // Written next to `path` and renamed into place, so it's never left
+        // half written.
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        let mut file = tempfile::NamedTempFile::new_in(dir)
+            .map_err(|e| format_err!(Other, "Can't create a temporary file in {:?}: {}", dir, e))?;
+        file.write_all(&data).map_err(|e| format_err!(Other, "Can't write {:?}: {}", path, e))?;
+        file.persist(path).map_err(|e| format_err!(Other, "Can't write {:?}: {}", path, e.error))?;
+        Ok(())
+    }
+
+    // Anonymizes the urls and titles with `f`, given the class of each, and
+    // removes anything else that could be personal. Returns how many strings
+    // were anonymized.
+    pub(crate) fn anonymize<F>(&mut self, mut f: F) -> usize where F: FnMut(ColumnClass, &str) -> String {
+        anonymize_value(&mut self.state, &mut f)
+    }
+}
+
+fn anonymize_value<F>(value: &mut Value, f: &mut F) -> usize where F: FnMut(ColumnClass, &str) -> String {
+    match *value {
+        Value::Object(ref mut map) => {
+            for key in REMOVED_KEYS {
+                map.remove(*key);
+            }
+            let mut count = 0;
+            for (key, value) in map.iter_mut() {
+                let class = if URL_KEYS.contains(&key.as_str()) {
+                    Some(ColumnClass::Url)
+                } else if TITLE_KEYS.contains(&key.as_str()) {
+                    Some(ColumnClass::Title)
+                } else {
+                    None
+                };
+                count += match (class, &*value) {
+                    (Some(class), Value::String(s)) if !s.is_empty() => {
+                        *value = Value::String(f(class, s));
+                        1
+                    }
+                    _ => anonymize_value(value, f),
+                };
+            }
+            count
+        }
+        Value::Array(ref mut values) => values.iter_mut().map(|v| anonymize_value(v, f)).sum(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn mozlz4_round_trips() {
+        let data = b"{\"windows\":[]} and some more text, and some more text".to_vec();
+        let file = mozlz4_encode(&data);
+        assert!(file.starts_with(MOZLZ4_MAGIC));
+        assert_eq!(mozlz4_decode(&file[MOZLZ4_MAGIC.len()..], Path::new("test")).unwrap(), data);
+        assert!(mozlz4_decode(&file[MOZLZ4_MAGIC.len()..MOZLZ4_MAGIC.len() + 2], Path::new("test")).is_err());
+    }
+
+    #[test]
+    fn scrubs_a_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessionstore.jsonlz4");
+        let state = json!({
+            "windows": [{
+                "tabs": [{
+                    "entries": [{
+                        "url": "https://example.com/secret",
+                        "title": "Secret page",
+                        "triggeringPrincipal_base64": "{\"1\":{\"0\":\"https://example.com/\"}}",
+                        "csp": "eyJ1cmwiOiJodHRwczovL2V4YW1wbGUuY29tLyJ9",
+                        "referrerInfo": "https://example.com/from",
+                        "children": [{"url": "https://example.com/frame", "srcdocData": "<p>secret</p>"}],
+                    }],
+                    "image": "https://example.com/favicon.ico",
+                    "iconLoadingPrincipal": "{\"1\":{\"0\":\"https://example.com/\"}}",
+                    "formdata": {"id": {"name": "secret"}},
+                    "index": 1,
+                }],
+                "cookies": [{"host": "example.com", "value": "secret"}],
+            }],
+        });
+        fs::write(&path, mozlz4_encode(&serde_json::to_vec(&state).unwrap())).unwrap();
+
+        let mut session = SessionStore::read(&path).unwrap().unwrap();
+        assert!(session.compressed);
+        assert_eq!(session.state, state);
+        let count = session.anonymize(|class, s| format!("{:?}:{}", class, s.len()));
+        assert_eq!(count, 4);
+        session.write(&path).unwrap();
+
+        let scrubbed = SessionStore::read(&path).unwrap().unwrap();
+        assert!(scrubbed.compressed);
+        assert_eq!(scrubbed.state, json!({
+            "windows": [{
+                "tabs": [{
+                    "entries": [{
+                        "url": "Url:26",
+                        "title": "Title:11",
+                        "children": [{"url": "Url:25"}],
+                    }],
+                    "image": "Url:31",
+                    "index": 1,
+                }],
+            }],
+        }));
+    }
+
+    #[test]
+    fn other_files_are_not_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("places.sqlite");
+        fs::write(&path, b"SQLite format 3\0").unwrap();
+        assert!(SessionStore::read(&path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rusqlite::{self, Connection, DatabaseName, OpenFlags};
+use tempfile;
+use url::Url;
+use ffi;
+
+use {Error, Result};
+use profiles::parent_lock_held;
+
+// Opens the database at `path`, which is someone's real data, so that SQLite
+// can't write to it whatever we do with the connection: read only, and by a
+// `mode=ro` URI too. If nothing can be writing to it (there's no journal or
+// -wal next to it, and Firefox doesn't have the profile locked), it's opened
+// as immutable as well, so SQLite doesn't even take locks on it or make a
+// -shm next to it. Immutable would miss a -wal's changes, or see a torn
+// database if it changed under us, so it's only used when it's safe.
+pub(crate) fn open_source(path: &Path) -> Result<Connection> {
+    let absolute = fs::canonicalize(path)?;
+    let mut uri = Url::from_file_path(&absolute)
+        .map_err(|_| format_err!(Other, "Can't make a URI for {:?}", path))?;
+    let idle = ["-wal", "-journal"].iter().all(|suffix| !sidecar(path, suffix).exists()) &&
+        !absolute.parent().and_then(parent_lock_held).unwrap_or(false);
+    uri.set_query(Some(if idle { "mode=ro&immutable=1" } else { "mode=ro" }));
+    debug!("Opening {:?} as {}", path, uri);
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    Ok(Connection::open_with_flags(uri.as_str(), flags)?)
+}
+
+// Copies the database at `path` into a temporary file using SQLite's online
+// backup API, which gives a consistent snapshot even if something (like a
+// running Firefox) is writing to it, rather than a torn copy. Going through
+// SQLite also picks up anything still in the `-wal` file.
+//
+// Firefox holds an exclusive lock on places.sqlite while it's running, so if
+// the database is locked we fall back to copying the files (including the
+// `-wal`) and taking the snapshot from the copies. That's only consistent if
+// Firefox doesn't write in the meantime, so we warn about it.
+pub(crate) fn snapshot(path: &Path) -> Result<tempfile::NamedTempFile> {
+    let temp = tempfile::NamedTempFile::new()?;
+    let source = open_source(path)?;
+    match source.backup(DatabaseName::Main, temp.path(), None) {
+        Ok(()) => {}
+        Err(rusqlite::Error::SqliteFailure(ffi::Error { code: ffi::ErrorCode::DatabaseBusy, .. }, _)) |
+        Err(rusqlite::Error::SqliteFailure(ffi::Error { code: ffi::ErrorCode::DatabaseLocked, .. }, _)) => {
+            warn!("{:?} is locked (is Firefox running?), copying it without locking. \
+                   Close Firefox first if the copy needs to be consistent", path);
+            drop(source);
+            let dir = tempfile::tempdir()?;
+            let copy = dir.path().join("places.sqlite");
+            fs::copy(path, &copy).map_err(|e| match e.raw_os_error() {
+                // Windows won't let anyone else read a file Firefox has
+                // locked (a sharing or lock violation).
+                Some(32) | Some(33) if cfg!(windows) => Error::SourceLocked(path.to_owned()),
+                _ => Error::Io(e),
+            })?;
+            // The -shm is just an index into the -wal, which SQLite rebuilds
+            // if it's missing, so it's safer not to copy a possibly stale one.
+            let wal = sidecar(path, "-wal");
+            if wal.exists() {
+                debug!("Copying {:?} along with it", wal);
+                fs::copy(&wal, sidecar(&copy, "-wal"))?;
+            }
+            // Opened read-write so SQLite can replay the -wal into the snapshot.
+            let source = Connection::open(&copy)?;
+            source.backup(DatabaseName::Main, temp.path(), None)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    debug!("Copied {:?} to {:?}", path, temp.path());
+    Ok(temp)
+}
+
+// The path of the `-wal` or `-shm` file that goes with the database at `path`.
+pub(crate) fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
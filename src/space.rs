@@ -0,0 +1,48 @@
+use std::path::Path;
+
+use Result;
+
+// Fails with a clear message if the filesystem `dir` is on has less than
+// `needed` bytes free, rather than letting SQLite fail with SQLITE_FULL
+// partway through. `what` says what the space is for. Passes if we can't
+// tell how much is free.
+pub(crate) fn check_free_space(dir: &Path, needed: u64, what: &str) -> Result<()> {
+    let available = match available_space(dir) {
+        Some(available) => available,
+        None => {
+            debug!("Can't tell how much space is free in {:?}, carrying on", dir);
+            return Ok(());
+        }
+    };
+    debug!("{} bytes free in {:?}, {} needs about {}", available, dir, what, needed);
+    if available < needed {
+        bail!(NoSpace, "Not enough free space in {:?}: {} needs about {} MB, but only {} MB is free",
+              dir, what, megabytes(needed), megabytes(available));
+    }
+    Ok(())
+}
+
+fn megabytes(bytes: u64) -> u64 {
+    bytes.div_ceil(1 << 20)
+}
+
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use libc;
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // Only what unprivileged users can use.
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn available_space(_dir: &Path) -> Option<u64> {
+    None
+}
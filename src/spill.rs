@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use rusqlite::Connection;
+use tempfile;
+
+// Roughly what a HashMap entry of two Strings costs beyond their text.
+const ENTRY_OVERHEAD: usize = 64;
+
+// A map of strings to strings that keeps entries in memory until they take
+// up more than a budget, and then moves them to a temporary SQLite database,
+// so that anonymizing a huge database doesn't need its every distinct string
+// in memory at once. Lookups check memory first, then the database.
+//
+// Errors using the database (like running out of disk) panic, as there's no
+// sensible way to carry on without the mapping.
+pub(crate) struct SpillMap {
+    memory: HashMap<String, String>,
+    bytes: usize,
+    budget: Option<usize>,
+    disk: Option<Disk>,
+}
+
+struct Disk {
+    conn: Connection,
+    // Removed when dropped, after the connection is closed.
+    _file: tempfile::NamedTempFile,
+}
+
+impl SpillMap {
+    pub(crate) fn new() -> SpillMap {
+        SpillMap { memory: HashMap::new(), bytes: 0, budget: None, disk: None }
+    }
+
+    // Spills to disk once the entries in memory take up more than `budget`
+    // bytes.
+    pub(crate) fn limit_memory(&mut self, budget: usize) {
+        self.budget = Some(budget);
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        if let Some(value) = self.memory.get(key) {
+            return Some(value.clone());
+        }
+        let disk = self.disk.as_ref()?;
+        let mut stmt = disk.conn.prepare_cached("SELECT value FROM map WHERE key = ?")
+            .expect("Can't read the spilled mapping");
+        let result = stmt.query_row(&[&key], |row| row.get(0));
+        match result {
+            Ok(value) => Some(value),
+            Err(::rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => panic!("Can't read the spilled mapping: {}", e),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Returns false (and leaves the map as it is) if `key` is already in it.
+    pub(crate) fn insert(&mut self, key: String, value: String) -> bool {
+        if self.contains_key(&key) {
+            return false;
+        }
+        let size = key.len() + value.len() + ENTRY_OVERHEAD;
+        if self.budget.is_some_and(|budget| self.bytes + size > budget) && !self.memory.is_empty() {
+            self.spill();
+        }
+        self.bytes += size;
+        self.memory.insert(key, value);
+        true
+    }
+
+    // Every entry, in no particular order.
+    pub(crate) fn entries(&self) -> Vec<(String, String)> {
+        let mut entries = self.memory.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+        if let Some(ref disk) = self.disk {
+            let mut stmt = disk.conn.prepare("SELECT key, value FROM map").expect("Can't read the spilled mapping");
+            let rows = stmt.query_map(&[], |row| (row.get(0), row.get(1))).expect("Can't read the spilled mapping");
+            entries.extend(rows.map(|row| row.expect("Can't read the spilled mapping")));
+        }
+        entries
+    }
+
+    // Moves everything in memory to the database.
+    fn spill(&mut self) {
+        if self.disk.is_none() {
+            let file = tempfile::NamedTempFile::new().expect("Can't create a file to spill the mapping to");
+            let conn = Connection::open(file.path()).expect("Can't open the file to spill the mapping to");
+            // It's thrown away at the end, so it needn't survive a crash.
+            conn.execute_batch("
+                PRAGMA journal_mode = OFF;
+                PRAGMA synchronous = OFF;
+                CREATE TABLE map (key TEXT PRIMARY KEY, value TEXT NOT NULL) WITHOUT ROWID;
+            ").expect("Can't set up the file to spill the mapping to");
+            debug!("Spilling the mapping to {:?}", file.path());
+            self.disk = Some(Disk { conn, _file: file });
+        }
+        let disk = self.disk.as_ref().expect("Made above");
+        disk.conn.execute_batch("BEGIN").expect("Can't spill the mapping");
+        {
+            let mut stmt = disk.conn.prepare_cached("INSERT INTO map (key, value) VALUES (?, ?)")
+                .expect("Can't spill the mapping");
+            for (key, value) in self.memory.drain() {
+                stmt.execute(&[&key, &value]).expect("Can't spill the mapping");
+            }
+        }
+        disk.conn.execute_batch("COMMIT").expect("Can't spill the mapping");
+        self.bytes = 0;
+    }
+}
+
+// Clones spill to a database of their own.
+impl Clone for SpillMap {
+    fn clone(&self) -> SpillMap {
+        let mut map = SpillMap::new();
+        map.budget = self.budget;
+        for (key, value) in self.entries() {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl fmt::Debug for SpillMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpillMap")
+            .field("in_memory", &self.memory.len())
+            .field("spilled", &self.disk.is_some())
+            .finish()
+    }
+}
+
+// A set of strings that spills to disk like `SpillMap`.
+#[derive(Clone, Debug)]
+pub(crate) struct SpillSet(SpillMap);
+
+impl SpillSet {
+    pub(crate) fn new() -> SpillSet {
+        SpillSet(SpillMap::new())
+    }
+
+    pub(crate) fn limit_memory(&mut self, budget: usize) {
+        self.0.limit_memory(budget);
+    }
+
+    pub(crate) fn contains(&self, value: &str) -> bool {
+        self.0.contains_key(value)
+    }
+
+    // Returns false if `value` was in the set already.
+    pub(crate) fn insert(&mut self, value: String) -> bool {
+        self.0.insert(value, String::new())
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use rusqlite::{Connection, OpenFlags, Rows};
+use rusqlite::types::Value;
+use serde_json;
+use url::Url;
+
+use Result;
+use anonymizer::StringAnonymizer;
+use decompress::decompress_source;
+use schema::{ColumnClass, TableInfo, Exclusions, BOOKMARK_ROOT_GUIDS, REFERENCES, UNIQUE_KEYS, read_tables};
+use snapshot::snapshot;
+
+// Compares every cell in `output` with the matching cell (by rowid) in
+// `source`. Text with letters or digits must have been changed, and everything
+// else must be identical. Reports and fails on any cell that doesn't hold up.
+// `cleared` columns may change even though they aren't text, and `kept`
+// columns may keep their text, as may any text `kept_value` accepts for the
+// column's class.
+pub(crate) fn thorough_verify(source: &Path, output: &Path, tables: &[TableInfo],
+                              exclusions: &Exclusions, cleared: &[(&str, &str)],
+                              kept: &[(&str, &str)], kept_value: &dyn Fn(ColumnClass, &str) -> bool)
+    -> Result<()>
+{
+    let source = Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let output = Connection::open_with_flags(output, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut problems = 0;
+    for info in tables {
+        let mut skipped = HashSet::new();
+        if let Some(cond) = exclusions.condition(&info.name) {
+            let mut stmt = source.prepare(&format!("SELECT rowid FROM {} WHERE {}", info.name, cond))?;
+            let mut rows = stmt.query(&[])?;
+            while let Some(row) = rows.next() {
+                skipped.insert(row?.get_checked::<_, i64>(0)?);
+            }
+        }
+        let sql = format!("SELECT rowid, * FROM {} ORDER BY rowid", info.name);
+        let (mut src_stmt, mut out_stmt) = match (source.prepare(&sql), output.prepare(&sql)) {
+            (Ok(a), Ok(b)) => (a, b),
+            _ => {
+                warn!("Can't verify {} (it has no rowid to match rows by), skipping", info.name);
+                continue;
+            }
+        };
+        let mut src_rows = src_stmt.query(&[])?;
+        let mut out_rows = out_stmt.query(&[])?;
+        let mut out_next = next_row(&mut out_rows)?;
+        let mut cells = 0;
+        while let Some((rowid, before_row)) = next_row(&mut src_rows)? {
+            let in_output = out_next.as_ref().map(|&(id, _)| id == rowid).unwrap_or(false);
+            let out_row = if in_output {
+                let row = out_next.take();
+                out_next = next_row(&mut out_rows)?;
+                row
+            } else {
+                None
+            };
+            // Rows that were handled specially follow their own rules (or
+            // were deleted), so skip them.
+            if skipped.contains(&rowid) {
+                continue;
+            }
+            let after_row = match out_row {
+                Some((_, row)) => row,
+                None => {
+                    warn!("{}: row {} is missing from the output", info.name, rowid);
+                    problems += 1;
+                    break;
+                }
+            };
+            for (((col, &class), before), after) in info.cols.iter().zip(&info.classes)
+                .zip(before_row).zip(after_row)
+            {
+                cells += 1;
+                let cleared = cleared.contains(&(info.name.as_str(), col.as_str()));
+                let kept = kept.contains(&(info.name.as_str(), col.as_str()));
+                match before {
+                    // Text without any letters or digits (like the "." rev_host of
+                    // a file url) has nothing to anonymize, so may be unchanged.
+                    Value::Text(ref t) if !t.is_empty() => if before == after && !kept &&
+                                                              t.chars().any(|c| c.is_alphanumeric()) &&
+                                                              !kept_value(class, t) {
+                        warn!("Possible leak: {}.{} (rowid {}) still has its original value",
+                              info.name, col, rowid);
+                        problems += 1;
+                    },
+                    _ => if before != after && !cleared {
+                        warn!("Possible corruption: {}.{} (rowid {}) changed from {:?} to {:?}",
+                              info.name, col, rowid, before, after);
+                        problems += 1;
+                    },
+                }
+            }
+        }
+        if out_next.is_some() {
+            warn!("{}: output has rows the source doesn't", info.name);
+            problems += 1;
+        }
+        debug!("Verified {} cells in {}", cells, info.name);
+    }
+    if problems != 0 {
+        bail!(Verification, "Verification found {} problem(s)", problems);
+    }
+    Ok(())
+}
+
+// Fails if the output has references that don't resolve, or duplicates of
+// what should be unique, that the source didn't: from replacements that
+// collided, say, or rows that were removed while others still refer to them.
+// What was already broken in the source is left for Firefox to cope with.
+pub(crate) fn check_references(source: &Path, output: &Path) -> Result<()> {
+    let source = Connection::open_with_flags(source, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let output = Connection::open_with_flags(output, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut problems = 0;
+    let mut compare = |what: String, sql: String| {
+        // Tables and columns the database doesn't have are nothing to check.
+        let count = |conn: &Connection| conn.query_row(&sql, &[], |row| row.get::<_, i64>(0)).ok();
+        if let (Some(before), Some(after)) = (count(&source), count(&output)) {
+            if after > before {
+                warn!("{} {}, but the source only has {}", after, what, before);
+                problems += 1;
+            }
+        }
+    };
+    for &(table, col, target, target_col) in REFERENCES {
+        compare(format!("{}.{} that don't match any {}.{}", table, col, target, target_col),
+                format!("SELECT count(*) FROM {0} WHERE {1} IS NOT NULL AND {1} NOT IN (SELECT {3} FROM {2})",
+                        table, col, target, target_col));
+    }
+    for &(table, cols) in UNIQUE_KEYS {
+        let not_null = cols.iter().map(|col| format!("{} IS NOT NULL", col)).collect::<Vec<_>>();
+        compare(format!("duplicated {}({})", table, cols.join(", ")),
+                format!("SELECT count(*) FROM (SELECT 1 FROM {} WHERE {} GROUP BY {} HAVING count(*) > 1)",
+                        table, not_null.join(" AND "), cols.join(", ")));
+    }
+    if problems != 0 {
+        bail!(Verification, "The output has {} kind(s) of broken reference or duplicated key the source didn't", problems);
+    }
+    Ok(())
+}
+
+// How many of SQLite's complaints about a corrupt database we show.
+const MAX_INTEGRITY_ERRORS: i32 = 10;
+
+// Fails, with what's wrong, if the database at `path` is corrupt.
+// `quick_check` looks at everything but whether indexes match their tables,
+// which `integrity_check` adds, at the cost of taking a lot longer.
+pub(crate) fn check_integrity(path: &Path, full: bool, what: &str) -> Result<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let pragma = if full { "integrity_check" } else { "quick_check" };
+    let problems = (|| -> ::rusqlite::Result<Vec<String>> {
+        let mut stmt = conn.prepare(&format!("PRAGMA {}({})", pragma, MAX_INTEGRITY_ERRORS))?;
+        let rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+        rows.collect()
+    })().unwrap_or_else(|e| vec![e.to_string()]);
+    if problems != ["ok"] {
+        bail!(Corrupt, "{} is corrupt ({} says: {})", what, pragma, problems.join("; "));
+    }
+    debug!("{} passed {}", what, pragma);
+    Ok(())
+}
+
+// The next row's rowid and values, for queries that select the rowid first.
+fn next_row(rows: &mut Rows) -> Result<Option<(i64, Vec<Value>)>> {
+    let row = match rows.next() {
+        Some(row) => row?,
+        None => return Ok(None),
+    };
+    let values = (1..row.column_count()).map(|i| row.get_checked(i)).collect::<::std::result::Result<_, _>>()?;
+    Ok(Some((row.get_checked(0)?, values)))
+}
+
+/// A host, url or title from an original database that was found in its
+/// anonymized copy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Leak {
+    /// Where it was found, as `table.column`.
+    pub column: String,
+    pub rowid: i64,
+    /// What it is in the original: `host`, `url` or `title`.
+    pub kind: &'static str,
+    pub value: String,
+}
+
+// Shorter strings turn up by chance, and say little anyway.
+const MIN_LEAK_LEN: usize = 4;
+
+// Urls and titles at least this long are also looked for inside other text,
+// not just as whole words or strings. Shorter ones would turn up by chance.
+const MIN_SUBSTRING_LEN: usize = 12;
+
+/// Looks for every host, url and title in the database at `original` anywhere
+/// in the database at `anonymized`, including inside urls, JSON and other
+/// text, and returns where it found them. Hosts are matched as whole hosts,
+/// and urls and titles as whole words or strings, or anywhere in the text if
+/// they're at least 12 characters long. Firefox's own urls, the bookmark
+/// roots' titles, and `keep_hosts` (and their subdomains, and the urls on
+/// them) are kept by design, so they aren't looked for.
+pub fn find_leaks(original: &Path, anonymized: &Path, keep_hosts: &[String]) -> Result<Vec<Leak>> {
+    let mut wanted = HashMap::new();
+    {
+        let source = match decompress_source(original)? {
+            Some(decompressed) => decompressed,
+            None => snapshot(original)?,
+        };
+        let conn = Connection::open_with_flags(source.path(), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut kept = StringAnonymizer::default();
+        kept.keep_hosts(keep_hosts.iter().cloned());
+        for info in read_tables(&conn, false)? {
+            let roots = info.cols.iter().any(|c| c == "guid") &&
+                (info.name == "moz_bookmarks" || info.name == "moz_bookmarks_synced");
+            for (col, &class) in info.cols.iter().zip(&info.classes) {
+                let kind = match class {
+                    ColumnClass::Url => "url",
+                    ColumnClass::Host | ColumnClass::RevHost => "host",
+                    ColumnClass::Title => "title",
+                    _ => continue,
+                };
+                let mut sql = format!("SELECT {0} FROM {1} WHERE typeof({0}) = 'text'", col, info.name);
+                if roots {
+                    sql.push_str(&format!(" AND guid NOT IN {}", BOOKMARK_ROOT_GUIDS));
+                }
+                let mut stmt = conn.prepare(&sql)?;
+                for value in stmt.query_map(&[], |row| row.get::<_, String>(0))? {
+                    let value = value?;
+                    match class {
+                        ColumnClass::Url if kept.is_kept_internal_url(&value) || kept.is_kept_url(&value) => {}
+                        ColumnClass::Url => {
+                            if let Ok(url) = Url::parse(&value) {
+                                if let Some(host) = url.host_str() {
+                                    wanted.insert(host.to_lowercase(), "host");
+                                }
+                                // Like the page `about:reader?url=...` shows.
+                                for (_, inner) in url.query_pairs().filter(|(_, inner)| !kept.is_kept_url(inner)) {
+                                    if let Some(host) = Url::parse(&inner).ok().and_then(|u| u.host_str().map(String::from)) {
+                                        wanted.insert(host.to_lowercase(), "host");
+                                        wanted.insert(inner.into_owned(), kind);
+                                    }
+                                }
+                            }
+                            wanted.insert(value, kind);
+                        }
+                        ColumnClass::RevHost | ColumnClass::Host => {
+                            let host = match class {
+                                ColumnClass::RevHost => value.trim_end_matches('.').chars().rev().collect::<String>(),
+                                _ => value.trim_start_matches('.').into(),
+                            };
+                            if !kept.is_kept_host(&host) {
+                                wanted.insert(host.to_lowercase(), kind);
+                            }
+                        }
+                        _ => { wanted.insert(value, kind); }
+                    }
+                }
+            }
+        }
+    }
+    wanted.retain(|value, _| value.chars().count() >= MIN_LEAK_LEN);
+    debug!("Looking for {} hosts, urls and titles", wanted.len());
+    // The long urls and titles by their first characters, to look for at
+    // each position in the text.
+    let mut by_prefix = HashMap::<String, Vec<(&str, &'static str)>>::new();
+    for (value, &kind) in &wanted {
+        if kind != "host" && value.chars().count() >= MIN_SUBSTRING_LEN {
+            let prefix = value.chars().take(MIN_SUBSTRING_LEN).collect::<String>();
+            by_prefix.entry(prefix).or_default().push((value, kind));
+        }
+    }
+
+    let conn = Connection::open_with_flags(anonymized, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut leaks = vec![];
+    for info in read_tables(&conn, false)? {
+        for col in &info.cols {
+            let sql = format!("SELECT rowid, {0} FROM {1} WHERE typeof({0}) = 'text'", col, info.name);
+            let mut stmt = match conn.prepare(&sql) {
+                Ok(stmt) => stmt,
+                Err(_) => {
+                    // WITHOUT ROWID tables, which we identify rows of by 0.
+                    conn.prepare(&format!("SELECT 0, {0} FROM {1} WHERE typeof({0}) = 'text'", col, info.name))?
+                }
+            };
+            for row in stmt.query_map(&[], |row| (row.get::<_, i64>(0), row.get::<_, String>(1)))? {
+                let (rowid, text) = row?;
+                let mut found = HashSet::new();
+                let mut matches = leak_candidates(&text).into_iter()
+                    .filter_map(|candidate| wanted.get(&candidate).map(|&kind| (candidate, kind)))
+                    .collect::<Vec<_>>();
+                let starts = text.char_indices().map(|(i, _)| i).chain(Some(text.len())).collect::<Vec<_>>();
+                for window in starts.windows(MIN_SUBSTRING_LEN + 1) {
+                    let (start, end) = (window[0], window[MIN_SUBSTRING_LEN]);
+                    for &(value, kind) in by_prefix.get(&text[start..end]).into_iter().flatten() {
+                        if text[start..].starts_with(value) {
+                            matches.push((value.to_owned(), kind));
+                        }
+                    }
+                }
+                for (value, kind) in matches {
+                    if found.insert(value.clone()) {
+                        leaks.push(Leak { column: format!("{}.{}", info.name, col), rowid, kind, value });
+                    }
+                }
+            }
+        }
+    }
+    Ok(leaks)
+}
+
+// Everything in `text` that could be a leaked host, url or title: the text
+// itself, the strings in it if it's JSON, the words and urls in it, and the
+// hosts (and their parent domains) in it.
+fn leak_candidates(text: &str) -> Vec<String> {
+    let mut candidates = vec![text.to_owned(), text.trim().to_owned()];
+    if let Ok(json) = serde_json::from_str::<serde_json::Value>(text) {
+        let mut stack = vec![json];
+        while let Some(value) = stack.pop() {
+            match value {
+                serde_json::Value::String(s) => candidates.extend(leak_candidates(&s)),
+                serde_json::Value::Array(values) => stack.extend(values),
+                serde_json::Value::Object(map) => stack.extend(map.into_iter().map(|(_, v)| v)),
+                _ => {}
+            }
+        }
+    }
+    for word in text.split(|c: char| c.is_whitespace() || "\"'<>()[]{},;|".contains(c)) {
+        if !word.is_empty() {
+            candidates.push(word.to_owned());
+        }
+    }
+    for host in text.split(|c: char| !(c.is_alphanumeric() || c == '.' || c == '-' || c == '_')) {
+        let mut domain = host.trim_matches('.').to_lowercase();
+        while domain.contains('.') {
+            let parent = domain[domain.find('.').unwrap() + 1..].to_owned();
+            candidates.push(domain);
+            domain = parent;
+        }
+        candidates.push(domain);
+    }
+    candidates
+}
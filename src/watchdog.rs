@@ -0,0 +1,43 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rusqlite::Connection;
+use ffi;
+
+// Interrupts whatever is running on a connection once a time budget runs out.
+// `stop` must be called before the connection is closed.
+pub(crate) struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+// The raw connection handle, which is fine to pass to `sqlite3_interrupt` from
+// another thread for as long as the connection stays open.
+struct InterruptHandle(*mut ffi::sqlite3);
+unsafe impl Send for InterruptHandle {}
+
+impl Watchdog {
+    pub(crate) fn start(conn: &Connection, budget: Duration) -> Watchdog {
+        let (done, timer) = mpsc::channel::<()>();
+        let handle = InterruptHandle(unsafe { conn.handle() });
+        let thread = thread::spawn(move || {
+            let handle = handle;
+            match timer.recv_timeout(budget) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    warn!("Ran out of time, interrupting");
+                    unsafe { ffi::sqlite3_interrupt(handle.0) };
+                    true
+                }
+                _ => false,
+            }
+        });
+        Watchdog { done, thread }
+    }
+
+    // Returns true if the watchdog fired.
+    pub(crate) fn stop(self) -> bool {
+        let _ = self.done.send(());
+        self.thread.join().unwrap_or(false)
+    }
+}